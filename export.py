@@ -1,4 +1,5 @@
 #!/usr/bin/env python3
+import onnx
 import torch
 import torch.nn as nn
 import torchvision
@@ -194,3 +195,14 @@ print("Saving model to", output_filename)
 
 torch.onnx.export(model_jit, random_data, output_filename, verbose=False, opset_version=12, input_names=['input'], output_names=['output'])
 
+# tell the plugin how to feed the model, so that it doesn't have to guess
+onnx_model = onnx.load(output_filename)
+onnx.helper.set_model_props(onnx_model, {
+    'tile_height': str(IMAGE_SIZE_H),
+    'tile_width': str(IMAGE_SIZE_W),
+    'normalization_mean': '0.5',
+    'normalization_std': '0.5',
+    'threshold': '0.0005',
+})
+onnx.save(onnx_model, output_filename)
+
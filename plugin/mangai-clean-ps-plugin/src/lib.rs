@@ -8,10 +8,7 @@ mod point;
 mod progress;
 mod rect;
 
-use ndarray::{
-    ArrayView2, ArrayView3, ArrayViewD, ArrayViewMut2, ArrayViewMut3, ArrayViewMutD, Ix2, Ix3,
-    Shape, ShapeBuilder,
-};
+use ndarray::{ArrayView3, ArrayViewMut3, Ix3, Shape, ShapeBuilder};
 use num_enum::TryFromPrimitive;
 use point::Point;
 use ps_sdk_sys::{int16, int32, Boolean, FilterRecord};
@@ -40,8 +37,6 @@ impl PluginBaseParams {
         }
     }
 
-    // TODO: support user abort
-    #[allow(unused)]
     pub fn is_aborted(&self) -> bool {
         unsafe { (self.test_abort_cb)() != 0 }
     }
@@ -120,6 +115,10 @@ struct PluginStartParams {
     filter_rect: Rect,
     image_mode: ImageMode,
     depth: i32,
+    /// Whether the layer has a transparency plane, which comes right after its colour planes
+    ///
+    /// `planes` also counts the layer masks, alpha channels and saved selections, which aren't transparency
+    has_transparency: bool,
 }
 
 impl PluginStartParams {
@@ -132,6 +131,17 @@ impl PluginStartParams {
             filter_rect: big_data.filterRect32.into(),
             image_mode: filter_param_block.imageMode.try_into().unwrap(),
             depth: filter_param_block.depth,
+            has_transparency: filter_param_block.inLayerPlanes > 0
+                && filter_param_block.inTransparencyMask > 0,
+        }
+    }
+
+    /// Number of colour planes of the supported image modes
+    fn color_planes(&self) -> Option<i16> {
+        match (self.image_mode, self.depth) {
+            (ImageMode::RGBColor, 8) => Some(3),
+            (ImageMode::GrayScale, 8) => Some(1),
+            _ => None,
         }
     }
 }
@@ -207,8 +217,8 @@ impl PluginStartResult {
 struct PluginContinueParams<'a> {
     in_rect: Rect,
     out_rect: Rect,
-    in_data: ArrayViewD<'a, u8>,
-    out_data: ArrayViewMutD<'a, u8>,
+    in_data: ArrayView3<'a, u8>,
+    out_data: ArrayViewMut3<'a, u8>,
     phantom: PhantomData<&'a mut ()>,
 }
 
@@ -231,46 +241,28 @@ impl<'a> PluginContinueParams<'a> {
         let in_width = (big_data.inRect32.right - big_data.inRect32.left) as usize;
         let in_stride = filter_param_block.inRowBytes as usize;
 
-        let mode: ImageMode = filter_param_block.imageMode.try_into().unwrap();
-
-        let in_data = match mode {
-            ImageMode::GrayScale => unsafe {
-                ArrayView2::from_shape_ptr(
-                    Shape::from(Ix2(in_height, in_width)).strides(Ix2(in_stride, 1)),
-                    filter_param_block.inData as *const u8,
-                )
-            }
-            .into_dyn(),
-            ImageMode::RGBColor => unsafe {
-                ArrayView3::from_shape_ptr(
-                    Shape::from(Ix3(3, in_height, in_width)).strides(Ix3(1, in_stride, 3)),
-                    filter_param_block.inData as *const u8,
-                )
-            }
-            .into_dyn(),
-            _ => unimplemented!(),
+        // planes are interleaved, we get the ones requested in `start` (the colour ones, followed by the transparency
+        // if the layer has one) as the channels of the page
+        let channels = (filter_param_block.inHiPlane - filter_param_block.inLoPlane + 1) as usize;
+
+        let in_data = unsafe {
+            ArrayView3::from_shape_ptr(
+                Shape::from(Ix3(channels, in_height, in_width))
+                    .strides(Ix3(1, in_stride, channels)),
+                filter_param_block.inData as *const u8,
+            )
         };
 
         let out_height = (big_data.outRect32.bottom - big_data.outRect32.top) as usize;
         let out_width = (big_data.outRect32.right - big_data.outRect32.left) as usize;
         let out_stride = filter_param_block.outRowBytes as usize;
 
-        let out_data = match mode {
-            ImageMode::GrayScale => unsafe {
-                ArrayViewMut2::from_shape_ptr(
-                    Shape::from(Ix2(out_height, out_width)).strides(Ix2(out_stride, 1)),
-                    filter_param_block.outData as *mut u8,
-                )
-            }
-            .into_dyn(),
-            ImageMode::RGBColor => unsafe {
-                ArrayViewMut3::from_shape_ptr(
-                    Shape::from(Ix3(3, out_height, out_width)).strides(Ix3(1, out_stride, 3)),
-                    filter_param_block.outData as *mut u8,
-                )
-            }
-            .into_dyn(),
-            _ => unimplemented!(),
+        let out_data = unsafe {
+            ArrayViewMut3::from_shape_ptr(
+                Shape::from(Ix3(channels, out_height, out_width))
+                    .strides(Ix3(1, out_stride, channels)),
+                filter_param_block.outData as *mut u8,
+            )
         };
 
         Self {
@@ -298,6 +290,8 @@ impl PluginContinueResult {
 #[repr(i16)]
 enum FilterError {
     Other = -1,
+    // userCanceledErr, it's not a part of the generated bindings
+    UserCanceled = -128,
 
     BadParameters = ps_sdk_sys::filterBadParameters as i16,
     BadMode = ps_sdk_sys::filterBadMode as i16,
@@ -334,25 +328,25 @@ fn start(
     // Set initial image rectangles to process.
     // and, actually, this is where most of the processing should be done
 
-    if !matches!(
-        (start.image_mode, start.depth, start.planes),
-        (ImageMode::RGBColor, 8, 3 | 4) | (ImageMode::GrayScale, 8, 1 | 2)
-    ) {
-        error!(
-            "Bad mode: image_mode={:?}, depth={}, planes={} (support only RGB8 and GrayScale8)",
-            start.image_mode, start.depth, start.planes
-        );
-        return Err(FilterError::BadMode);
-    }
+    let color_planes = match start.color_planes() {
+        Some(color_planes) if start.planes >= color_planes => color_planes,
+        _ => {
+            error!(
+                "Bad mode: image_mode={:?}, depth={}, planes={} (support only RGB8 and GrayScale8)",
+                start.image_mode, start.depth, start.planes
+            );
+            return Err(FilterError::BadMode);
+        }
+    };
 
     info!("{:#?}", start);
 
-    let hi_plane = match (start.image_mode, start.planes) {
-        (ImageMode::RGBColor, 3) => 2,
-        (ImageMode::RGBColor, 4) => 2, // no alpha!
-        (ImageMode::GrayScale, 1) => 0,
-        (ImageMode::GrayScale, 2) => 0, // no alpha!
-        _ => unreachable!(),
+    // the transparency plane (if any) goes right after the colour ones, the core crate keeps it as is. The other
+    // planes (layer masks, alpha channels, saved selections) are not requested
+    let hi_plane = if start.has_transparency && start.planes > color_planes {
+        color_planes
+    } else {
+        color_planes - 1
     };
 
     info!("Requesting planes 0..={}", hi_plane);
@@ -383,37 +377,48 @@ fn r#continue(
 
     info!("{:#?}", r#continue);
 
-    progress::run_with_progress(plugin, |progress| {
+    let result = progress::run_with_progress(plugin, |progress| {
         info!("Loading mangai model...");
-        let clean = mangai_clean::MangaiClean::new(progress).unwrap();
+        let clean = mangai_clean::MangaiClean::new(progress)?;
         info!("Loaded mangai clean model");
 
-        match r#continue.in_data.shape() {
-            [_, _] => {
-                // grayscale
-                let in_data = r#continue.in_data.into_dimensionality::<Ix2>().unwrap();
-                let out_data = r#continue.out_data.into_dimensionality::<Ix2>().unwrap();
-
-                clean.clean_grayscale_page(in_data, out_data, progress);
-            }
-            [3, _, _] => {
-                // RGB
-                let in_data = r#continue.in_data.into_dimensionality::<Ix3>().unwrap();
-                let out_data = r#continue.out_data.into_dimensionality::<Ix3>().unwrap();
-
-                clean.clean_page(in_data, out_data, progress);
-            }
-            _ => unimplemented!(),
-        }
+        let options = mangai_clean::CleanOptions::default();
+
+        clean.clean_page(
+            r#continue.in_data,
+            r#continue.out_data,
+            None,
+            None,
+            &options,
+            progress,
+        )?;
 
         info!("Cleaned page!");
+
+        Ok::<_, mangai_clean::MangaiError>(())
     });
 
+    result?.map_err(filter_error)?;
+
     Ok(PluginContinueResult {
         request: InOutRequest::empty(),
     })
 }
 
+/// The error code reported to Photoshop for a failed cleaning
+fn filter_error(err: mangai_clean::MangaiError) -> FilterError {
+    match err {
+        mangai_clean::MangaiError::Cancelled => {
+            info!("Cleaning was cancelled by the user");
+            FilterError::UserCanceled
+        }
+        err => {
+            error!("Failed to clean the page: {:?}", err);
+            FilterError::Other
+        }
+    }
+}
+
 fn finish(_plugin: &PluginBaseParams) -> Result<(), FilterError> {
     // Clean up. Pass back scripting parameters.
 
@@ -485,3 +490,28 @@ pub extern "C" fn PluginMain(
     //     msgbox::IconType::Info,
     // );
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mangai_clean::MangaiError;
+
+    #[test]
+    fn test_filter_error() {
+        assert!(matches!(
+            filter_error(MangaiError::Cancelled),
+            FilterError::UserCanceled
+        ));
+        assert!(matches!(
+            filter_error(MangaiError::InvalidScale { scale: 0.0 }),
+            FilterError::Other
+        ));
+        assert!(matches!(
+            filter_error(MangaiError::ScaledPageTooLarge {
+                height: 1,
+                width: 1
+            }),
+            FilterError::Other
+        ));
+    }
+}
@@ -5,12 +5,12 @@
 // honestly, progress reporting should be redesigned
 // but here we are
 
-use crate::PluginBaseParams;
+use crate::{FilterError, PluginBaseParams};
 use mangai_clean::{ProgressKind, ProgressReporter};
 use nwg::NativeUi;
 use std::cell::RefCell;
 use std::sync::{Arc, Mutex, Once};
-use tracing::info;
+use tracing::{error, info};
 
 #[derive(Default, Clone, Debug)]
 enum ProgressStatus {
@@ -143,10 +143,10 @@ impl ProgressReporter for GuiSenderProgress {
 
 static GUI_INIT: Once = Once::new();
 
-pub fn run_with_progress<'a>(
+pub fn run_with_progress<'a, R: Send>(
     ps: &PluginBaseParams,
-    func: impl FnOnce(&mut dyn ProgressReporter) + Send + 'a,
-) {
+    func: impl FnOnce(&mut dyn ProgressReporter) -> R + Send + 'a,
+) -> Result<R, FilterError> {
     // the custom UI is prone to crashing, so use PS's progress bar instead
     // GUI_INIT.call_once(|| {
     //     nwg::init().unwrap();
@@ -165,16 +165,25 @@ pub fn run_with_progress<'a>(
     //
     // nwg::dispatch_thread_events();
 
-    std::thread::scope(|s| {
-        s.spawn(|| {
-            let mut progress = PsProgressReporter::new(ps);
+    run_catching_panics(|| {
+        let mut progress = PsProgressReporter::new(ps);
 
-            info!("!!!!");
+        info!("!!!!");
 
-            func(&mut progress);
-        })
-        .join()
-        .unwrap();
+        func(&mut progress)
+    })
+}
+
+/// Runs `func` on a separate thread, a panic there is reported as an error instead of unwinding into the host
+fn run_catching_panics<R: Send>(func: impl FnOnce() -> R + Send) -> Result<R, FilterError> {
+    std::thread::scope(|s| s.spawn(func).join()).map_err(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+        error!("The cleaning thread panicked: {}", message);
+        FilterError::Other
     })
 }
 
@@ -203,4 +212,26 @@ impl<'a> ProgressReporter for PsProgressReporter<'a> {
     fn finish(&mut self) {
         self.params.report_progress(self.total, self.total);
     }
+
+    fn is_cancelled(&mut self) -> bool {
+        self.params.is_aborted()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_run_catching_panics() {
+        assert!(matches!(run_catching_panics(|| 42), Ok(42)));
+        assert!(matches!(
+            run_catching_panics(|| -> i32 { panic!("oops") }),
+            Err(FilterError::Other)
+        ));
+        assert!(matches!(
+            run_catching_panics(|| -> i32 { panic!("{}", String::from("oops")) }),
+            Err(FilterError::Other)
+        ));
+    }
 }
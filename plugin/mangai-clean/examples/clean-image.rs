@@ -1,18 +1,46 @@
 use camino::Utf8PathBuf;
 use clap::Parser;
-use mangai_clean::{MangaiClean, ProgressKind};
+use mangai_clean::ndarray::Axis;
+use mangai_clean::{CleanOptions, MangaiClean, PostProcessingOptions, ProgressKind};
 use nshare::{MutNdarray2, ToNdarray2};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Name of the person to greet
-    #[arg(short, long)]
-    input: Utf8PathBuf,
+    /// Image to clean
+    ///
+    /// Given several times, the images are treated as vertical strips of a single page (like webtoon slices), top to
+    /// bottom, so that the text crossing the image boundaries is cleaned as a whole
+    #[arg(short, long, required = true)]
+    input: Vec<Utf8PathBuf>,
+
+    /// Where to save the cleaned image, given once for each input
+    #[arg(short, long, required = true)]
+    output: Vec<Utf8PathBuf>,
+
+    /// Number of batches to process in parallel
+    #[arg(short, long, default_value_t = 1)]
+    workers: usize,
+
+    /// Number of batches to stack into a single inference call (requires a model with a dynamic batch axis)
+    #[arg(short, long, default_value_t = 1)]
+    batch_size: usize,
 
-    /// Number of times to greet
+    /// Number of flipped and rotated passes of each batch (up to 4), slower but finds more text
+    #[arg(short, long, default_value_t = 0)]
+    augmentations: usize,
+
+    /// Drop specks, close gaps and fill holes in the detected mask before it's dilated
     #[arg(short, long)]
-    output: Utf8PathBuf,
+    post_process: bool,
+
+    /// Image of the same size as the (single) input, only its non-black pixels get cleaned
+    #[arg(long)]
+    include: Option<Utf8PathBuf>,
+
+    /// Image of the same size as the (single) input, its non-black pixels are never modified
+    #[arg(long)]
+    exclude: Option<Utf8PathBuf>,
 }
 
 struct IndicatifProgress {
@@ -61,20 +89,84 @@ fn main() {
     tracing_subscriber::fmt::init();
 
     let args = Args::parse();
+    if args.input.len() != args.output.len() {
+        eprintln!(
+            "Expected an output for each of the {} inputs",
+            args.input.len()
+        );
+        std::process::exit(1);
+    }
 
-    println!("Loading the image...");
-    let image_image = image::open(args.input).unwrap();
-    let image = image_image.to_luma8().into_ndarray2();
+    println!("Loading the images...");
+    let images = args
+        .input
+        .iter()
+        .map(|path| image::open(path).unwrap().to_luma8().into_ndarray2())
+        .collect::<Vec<_>>();
+
+    let load_mask = |path: &Utf8PathBuf| {
+        image::open(path)
+            .unwrap()
+            .to_luma8()
+            .into_ndarray2()
+            .mapv(|value| value > 0)
+    };
+    let include_mask = args.include.as_ref().map(load_mask);
+    let exclude_mask = args.exclude.as_ref().map(load_mask);
+    if images.len() > 1 && (include_mask.is_some() || exclude_mask.is_some()) {
+        eprintln!("The include and exclude masks are only supported with a single input");
+        std::process::exit(1);
+    }
 
     let mut progress = IndicatifProgress::new();
 
-    let mut output_image = image::GrayImage::new(image_image.width(), image_image.height());
+    let mut output_images = images
+        .iter()
+        .map(|image| {
+            let (height, width) = image.dim();
+            image::GrayImage::new(width as u32, height as u32)
+        })
+        .collect::<Vec<_>>();
     println!("Loading the model...");
     let clean = MangaiClean::new(&mut progress).unwrap();
 
-    println!("Cleaning the image...");
-    clean.clean_grayscale_page(image.view(), output_image.mut_ndarray2(), &mut progress);
+    let options = CleanOptions {
+        num_workers: args.workers,
+        max_batch_size: args.batch_size,
+        augmentations: args.augmentations,
+        post_processing: args.post_process.then(PostProcessingOptions::default),
+        ..Default::default()
+    };
 
-    println!("Saving the image...");
-    output_image.save(args.output).unwrap();
+    if images.len() == 1 {
+        println!("Cleaning the image...");
+        clean
+            .clean_grayscale_page(
+                images[0].view(),
+                output_images[0].mut_ndarray2(),
+                include_mask.as_ref().map(|mask| mask.view()),
+                exclude_mask.as_ref().map(|mask| mask.view()),
+                &options,
+                &mut progress,
+            )
+            .unwrap();
+    } else {
+        println!("Cleaning {} strips as a single page...", images.len());
+        let strips_in = images
+            .iter()
+            .map(|image| image.view().insert_axis(Axis(0)))
+            .collect::<Vec<_>>();
+        let mut strips_out = output_images
+            .iter_mut()
+            .map(|image| image.mut_ndarray2().insert_axis(Axis(0)))
+            .collect::<Vec<_>>();
+        clean
+            .clean_strips(&strips_in, &mut strips_out, &options, &mut progress)
+            .unwrap();
+    }
+
+    println!("Saving the images...");
+    for (output_image, path) in output_images.iter().zip(&args.output) {
+        output_image.save(path).unwrap();
+    }
 }
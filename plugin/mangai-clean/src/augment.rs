@@ -0,0 +1,184 @@
+//! Test-time augmentation: running the model on flipped and rotated tiles, and merging the outputs back
+
+use crate::batcher::Batcher;
+use crate::blend::ProbabilityAccumulator;
+use crate::error::Result;
+use crate::options::{BlendMode, CleanOptions};
+use crate::sample::Sample;
+use crate::{page, pipeline, MangaiClean};
+use ndarray::{s, Array2, ArrayView2, ArrayView3, Zip};
+
+/// A transformation of the tile, which the model output can be transformed back from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Augmentation {
+    FlipHorizontal,
+    /// Counterclockwise
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+/// In the order they are applied with [`CleanOptions::augmentations`], the most useful first
+pub const AUGMENTATIONS: [Augmentation; 4] = [
+    Augmentation::FlipHorizontal,
+    Augmentation::Rotate90,
+    Augmentation::Rotate270,
+    Augmentation::Rotate180,
+];
+
+impl Augmentation {
+    /// Transforms a (channels, height, width) image, without copying
+    pub fn apply<'a, T>(self, image: ArrayView3<'a, T>) -> ArrayView3<'a, T> {
+        match self {
+            Augmentation::FlipHorizontal => image.slice_move(s![.., .., ..;-1]),
+            Augmentation::Rotate90 => image.permuted_axes([0, 2, 1]).slice_move(s![.., ..;-1, ..]),
+            Augmentation::Rotate180 => image.slice_move(s![.., ..;-1, ..;-1]),
+            Augmentation::Rotate270 => image.permuted_axes([0, 2, 1]).slice_move(s![.., .., ..;-1]),
+        }
+    }
+
+    /// Transforms the model output for the augmented image back to the original orientation
+    pub fn undo(self, output: ArrayView2<f32>) -> Array2<f32> {
+        match self {
+            Augmentation::FlipHorizontal => output.slice_move(s![.., ..;-1]),
+            Augmentation::Rotate90 => output.slice_move(s![..;-1, ..]).reversed_axes(),
+            Augmentation::Rotate180 => output.slice_move(s![..;-1, ..;-1]),
+            Augmentation::Rotate270 => output.slice_move(s![.., ..;-1]).reversed_axes(),
+        }
+        .as_standard_layout()
+        .into_owned()
+    }
+}
+
+/// Like [`MangaiClean::run_batches`], but for images of any size
+///
+/// Smaller images are padded, larger ones are split into batches. This is what the rotated tiles need, unless the
+/// tiles are square
+fn run_any_size<T: Sample>(
+    clean: &MangaiClean,
+    images_in: &[ArrayView3<T>],
+    options: &CleanOptions,
+) -> Result<Vec<Array2<f32>>> {
+    let (tile_height, tile_width) = clean.tile_size();
+    if images_in
+        .iter()
+        .all(|image| image.shape()[1..] == [tile_height, tile_width])
+    {
+        return clean.run_batches(images_in);
+    }
+
+    images_in
+        .iter()
+        .map(|image_in| {
+            let (_, height, width) = image_in.dim();
+            let padded_image = page::pad(image_in.view(), clean.tile_size());
+            let (_, padded_height, padded_width) = padded_image.dim();
+
+            let mut accumulator =
+                ProbabilityAccumulator::new(padded_height, padded_width, BlendMode::Max);
+            let batcher = Batcher::new(padded_height, padded_width, clean.tile_size(), 0)?;
+            let slices = batcher.iter().collect::<Vec<_>>();
+            for group in slices.chunks(clean.group_size(options)) {
+                let tiles = group
+                    .iter()
+                    .map(|slice| padded_image.slice(slice))
+                    .collect::<Vec<_>>();
+                for (slice, output) in group.iter().zip(clean.run_batches(&tiles)?) {
+                    accumulator.add(pipeline::batch_origin(slice), output.view());
+                }
+            }
+
+            Ok(accumulator.finish().slice_move(s![..height, ..width]))
+        })
+        .collect()
+}
+
+/// Runs the model on the tiles, and then on as many of their [`AUGMENTATIONS`] as [`CleanOptions::augmentations`] asks
+/// for, keeping the maximum of all the outputs
+pub fn run_augmented<T: Sample>(
+    clean: &MangaiClean,
+    images_in: &[ArrayView3<T>],
+    options: &CleanOptions,
+) -> Result<Vec<Array2<f32>>> {
+    let mut outputs = clean.run_batches(images_in)?;
+
+    for &augmentation in AUGMENTATIONS.iter().take(options.augmentations) {
+        let augmented_images = images_in
+            .iter()
+            .map(|image_in| augmentation.apply(image_in.view()))
+            .collect::<Vec<_>>();
+        let augmented_outputs = run_any_size(clean, &augmented_images, options)?;
+
+        for (output, augmented_output) in outputs.iter_mut().zip(augmented_outputs) {
+            let augmented_output = augmentation.undo(augmented_output.view());
+            Zip::from(output)
+                .and(&augmented_output)
+                .for_each(|a, &b| *a = a.max(b));
+        }
+    }
+
+    Ok(outputs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ndarray::{Array3, Axis};
+
+    #[test]
+    fn test_run_augmented() {
+        // non-square tiles, so that the rotated ones have to be padded and split
+        let clean = MangaiClean::stub((24, 16));
+        let tiles = (0..3)
+            .map(|i| {
+                Array3::from_shape_fn((3, 24, 16), |(_, y, x)| {
+                    if (y * 7 + x * 3 + i) % 5 < 2 {
+                        20u8
+                    } else {
+                        230
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let images_in = tiles.iter().map(|tile| tile.view()).collect::<Vec<_>>();
+
+        // the stub model works pixel by pixel, so the augmentations can't change anything
+        let expected = clean.run_batches(&images_in).unwrap();
+        assert!(expected[0].iter().any(|&p| p > 0.5));
+        for augmentations in 1..=4 {
+            let options = CleanOptions {
+                augmentations,
+                max_batch_size: 2,
+                ..Default::default()
+            };
+            let outputs = run_augmented(&clean, &images_in, &options).unwrap();
+            assert_eq!(outputs, expected, "{}", augmentations);
+        }
+    }
+
+    #[test]
+    fn test_undo() {
+        let image = Array3::from_shape_fn((1, 3, 5), |(_, y, x)| (y * 5 + x) as f32);
+        let original = image.index_axis(Axis(0), 0);
+
+        for augmentation in AUGMENTATIONS {
+            let augmented = augmentation.apply(image.view());
+            // a model which just passes the (single channel) input through
+            let output = augmentation.undo(augmented.index_axis(Axis(0), 0));
+            assert_eq!(output, original, "{:?}", augmentation);
+        }
+
+        assert_eq!(
+            Augmentation::Rotate90
+                .apply(image.view())
+                .index_axis(Axis(0), 0),
+            ndarray::arr2(&[
+                [4.0, 9.0, 14.0],
+                [3.0, 8.0, 13.0],
+                [2.0, 7.0, 12.0],
+                [1.0, 6.0, 11.0],
+                [0.0, 5.0, 10.0],
+            ])
+        );
+    }
+}
@@ -1,39 +1,80 @@
-use crate::model::{BATCH_HEIGHT, BATCH_WIDTH};
+use crate::error::{MangaiError, Result};
 use ndarray::{s, Ix3, SliceInfo, SliceInfoElem};
+use std::ops::Range;
+
+/// Placement of the tiles along one axis of the page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Tiles {
+    len: usize,
+    tile: usize,
+    count: usize,
+}
+
+impl Tiles {
+    /// `margin * 2` must be less than `tile`, so that the tiles move by at least a pixel
+    fn new(len: usize, tile: usize, margin: usize) -> Self {
+        assert!(len >= tile);
+        assert!(margin * 2 < tile);
+
+        // overlapping by twice the margin, the pixels closer than the margin to the edge of one tile are far enough
+        // into the next one
+        let max_stride = tile - margin * 2;
+        let count = 1 + (len - tile).div_ceil(max_stride);
+
+        Self { len, tile, count }
+    }
+
+    /// Mean distance between the starts of the neighbouring tiles, the actual ones are rounded to whole pixels
+    #[cfg(test)]
+    fn stride(&self) -> f64 {
+        if self.count == 1 {
+            0.0
+        } else {
+            (self.len - self.tile) as f64 / (self.count - 1) as f64
+        }
+    }
+
+    fn range(&self, i: usize) -> Range<usize> {
+        let start = if self.count == 1 {
+            0
+        } else {
+            i * (self.len - self.tile) / (self.count - 1)
+        };
+        start..start + self.tile
+    }
+}
 
+/// Splits the page into tiles of the model input size, going row by row
+///
+/// The tiles are spread evenly, with the first and the last ones touching the page borders. Each pixel is at least
+/// `margin` pixels away from the edges of some tile (not counting the edges on the page border), so the neighbouring
+/// tiles overlap by at least `margin * 2` pixels. The stride follows from that, it's the largest one that keeps the
+/// margin
 #[derive(Debug, Clone, Copy)]
 pub struct Batcher {
-    v_batches: usize,
-    h_batches: usize,
-    v_offset: f64,
-    h_offset: f64,
+    rows: Tiles,
+    columns: Tiles,
 }
 
 impl Batcher {
-    pub fn new(height: usize, width: usize) -> Self {
-        assert!(height >= BATCH_HEIGHT);
-        assert!(width >= BATCH_WIDTH);
-
-        let v_batches = (height + BATCH_HEIGHT - 1) / BATCH_HEIGHT;
-        let h_batches = (width + BATCH_WIDTH - 1) / BATCH_WIDTH;
-
-        fn map_inf_to_zero(x: f64) -> f64 {
-            if x.is_infinite() || x.is_nan() {
-                0.0
-            } else {
-                x
-            }
+    /// The page must be at least the size of a tile
+    ///
+    /// Fails if `margin` isn't less than half of the tile size, as the tiles then can't move while keeping it
+    pub fn new(
+        height: usize,
+        width: usize,
+        tile_size: (usize, usize),
+        margin: usize,
+    ) -> Result<Self> {
+        let (tile_height, tile_width) = tile_size;
+        if margin * 2 >= tile_height.min(tile_width) {
+            return Err(MangaiError::TileMarginTooLarge { margin, tile_size });
         }
 
-        let v_offset = map_inf_to_zero((height - BATCH_HEIGHT) as f64 / (v_batches - 1) as f64);
-        let h_offset = map_inf_to_zero((width - BATCH_WIDTH) as f64 / (h_batches - 1) as f64);
-
-        Self {
-            v_batches,
-            h_batches,
-            v_offset,
-            h_offset,
-        }
+        Ok(Self {
+            rows: Tiles::new(height, tile_height, margin),
+            columns: Tiles::new(width, tile_width, margin),
+        })
     }
 
     pub fn iter(&self) -> BatcherIter {
@@ -45,7 +86,7 @@ impl Batcher {
     }
 
     pub fn num_batches(&self) -> usize {
-        self.v_batches * self.h_batches
+        self.rows.count * self.columns.count
     }
 }
 
@@ -60,38 +101,34 @@ impl Iterator for BatcherIter {
     type Item = SliceInfo<[SliceInfoElem; 3], Ix3, Ix3>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.v >= self.batcher.v_batches {
+        if self.v >= self.batcher.rows.count {
             return None;
         }
 
-        let v_start = self.v as f64 * self.batcher.v_offset;
-        let v_end = v_start + BATCH_HEIGHT as f64;
-        let h_start = self.h as f64 * self.batcher.h_offset;
-        let h_end = h_start + BATCH_WIDTH as f64;
+        let rows = self.batcher.rows.range(self.v);
+        let columns = self.batcher.columns.range(self.h);
 
         self.h += 1;
-        if self.h >= self.batcher.h_batches {
+        if self.h >= self.batcher.columns.count {
             self.h = 0;
             self.v += 1;
         }
 
-        Some(s![
-            ..,
-            v_start as usize..v_end as usize,
-            h_start as usize..h_end as usize
-        ])
+        Some(s![.., rows, columns])
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        let remaining = (self.batcher.v_batches - self.v) * self.batcher.h_batches - self.h;
+        let remaining = (self.batcher.rows.count - self.v) * self.batcher.columns.count - self.h;
         (remaining, Some(remaining))
     }
 }
 
 #[cfg(test)]
 mod test {
+    use crate::model::{BATCH_HEIGHT, BATCH_WIDTH};
     use crate::ndarray;
     use ndarray::{Ix3, SliceInfo};
+    use proptest::prelude::*;
     use std::ops::Deref;
 
     fn deref_slice_info<T: Clone>(info: SliceInfo<T, Ix3, Ix3>) -> T {
@@ -102,11 +139,12 @@ mod test {
     fn test_batcher_single() {
         use super::*;
 
-        let batcher = Batcher::new(BATCH_HEIGHT, BATCH_WIDTH);
-        assert_eq!(batcher.v_batches, 1);
-        assert_eq!(batcher.h_batches, 1);
-        assert_eq!(batcher.v_offset, 0.0);
-        assert_eq!(batcher.h_offset, 0.0);
+        let batcher =
+            Batcher::new(BATCH_HEIGHT, BATCH_WIDTH, (BATCH_HEIGHT, BATCH_WIDTH), 0).unwrap();
+        assert_eq!(batcher.rows.count, 1);
+        assert_eq!(batcher.columns.count, 1);
+        assert_eq!(batcher.rows.stride(), 0.0);
+        assert_eq!(batcher.columns.stride(), 0.0);
 
         let slices = batcher.iter().map(deref_slice_info).collect::<Vec<_>>();
         assert_eq!(
@@ -119,11 +157,17 @@ mod test {
     fn test_batcher_mostly_intersects() {
         use super::*;
 
-        let batcher = Batcher::new(BATCH_HEIGHT + 1, BATCH_WIDTH);
-        assert_eq!(batcher.v_batches, 2);
-        assert_eq!(batcher.h_batches, 1);
-        assert_eq!(batcher.v_offset, 1.0);
-        assert_eq!(batcher.h_offset, 0.0);
+        let batcher = Batcher::new(
+            BATCH_HEIGHT + 1,
+            BATCH_WIDTH,
+            (BATCH_HEIGHT, BATCH_WIDTH),
+            0,
+        )
+        .unwrap();
+        assert_eq!(batcher.rows.count, 2);
+        assert_eq!(batcher.columns.count, 1);
+        assert_eq!(batcher.rows.stride(), 1.0);
+        assert_eq!(batcher.columns.stride(), 0.0);
 
         let slices = batcher.iter().map(deref_slice_info).collect::<Vec<_>>();
         assert_eq!(
@@ -140,11 +184,17 @@ mod test {
     fn test_batcher_mostly_intersects2() {
         use super::*;
 
-        let batcher = Batcher::new(BATCH_HEIGHT + 1, BATCH_WIDTH + 1);
-        assert_eq!(batcher.v_batches, 2);
-        assert_eq!(batcher.h_batches, 2);
-        assert_eq!(batcher.v_offset, 1.0);
-        assert_eq!(batcher.h_offset, 1.0);
+        let batcher = Batcher::new(
+            BATCH_HEIGHT + 1,
+            BATCH_WIDTH + 1,
+            (BATCH_HEIGHT, BATCH_WIDTH),
+            0,
+        )
+        .unwrap();
+        assert_eq!(batcher.rows.count, 2);
+        assert_eq!(batcher.columns.count, 2);
+        assert_eq!(batcher.rows.stride(), 1.0);
+        assert_eq!(batcher.columns.stride(), 1.0);
 
         let slices = batcher.iter().map(deref_slice_info).collect::<Vec<_>>();
         assert_eq!(
@@ -163,13 +213,19 @@ mod test {
     fn test_batcher_mostly_not_intersects() {
         use super::*;
 
-        let batcher = Batcher::new(BATCH_HEIGHT * 2 - 1, BATCH_WIDTH * 2);
-        assert_eq!(batcher.v_batches, 2);
-        assert_eq!(batcher.h_batches, 2);
-        assert_eq!(batcher.v_offset, (BATCH_HEIGHT - 1) as f64);
-        assert_eq!(batcher.h_offset, BATCH_WIDTH as f64);
+        let batcher = Batcher::new(
+            BATCH_HEIGHT * 2 - 1,
+            BATCH_WIDTH * 2,
+            (BATCH_HEIGHT, BATCH_WIDTH),
+            0,
+        )
+        .unwrap();
+        assert_eq!(batcher.rows.count, 2);
+        assert_eq!(batcher.columns.count, 2);
+        assert_eq!(batcher.rows.stride(), (BATCH_HEIGHT - 1) as f64);
+        assert_eq!(batcher.columns.stride(), BATCH_WIDTH as f64);
 
-        let mut slices = batcher.iter().map(deref_slice_info).collect::<Vec<_>>();
+        let slices = batcher.iter().map(deref_slice_info).collect::<Vec<_>>();
         assert_eq!(
             &slices,
             &[
@@ -190,11 +246,17 @@ mod test {
     fn test_batcher_not_intersects() {
         use super::*;
 
-        let batcher = Batcher::new(BATCH_HEIGHT * 2, BATCH_WIDTH * 2);
-        assert_eq!(batcher.v_batches, 2);
-        assert_eq!(batcher.h_batches, 2);
-        assert_eq!(batcher.v_offset, BATCH_HEIGHT as f64);
-        assert_eq!(batcher.h_offset, BATCH_WIDTH as f64);
+        let batcher = Batcher::new(
+            BATCH_HEIGHT * 2,
+            BATCH_WIDTH * 2,
+            (BATCH_HEIGHT, BATCH_WIDTH),
+            0,
+        )
+        .unwrap();
+        assert_eq!(batcher.rows.count, 2);
+        assert_eq!(batcher.columns.count, 2);
+        assert_eq!(batcher.rows.stride(), BATCH_HEIGHT as f64);
+        assert_eq!(batcher.columns.stride(), BATCH_WIDTH as f64);
 
         let slices = batcher.iter().map(deref_slice_info).collect::<Vec<_>>();
         assert_eq!(
@@ -212,4 +274,54 @@ mod test {
             .map(deref_slice_info)
         );
     }
+
+    #[test]
+    fn test_batcher_margin_too_large() {
+        use super::*;
+
+        assert!(Batcher::new(100, 100, (20, 30), 9).is_ok());
+        assert!(matches!(
+            Batcher::new(100, 100, (20, 30), 10),
+            Err(MangaiError::TileMarginTooLarge { margin: 10, .. })
+        ));
+    }
+
+    proptest! {
+        #[test]
+        fn test_batcher_covers_page(
+            tile_height in 1usize..200,
+            tile_width in 1usize..200,
+            extra_height in 0usize..2000,
+            extra_width in 0usize..500,
+            margin in 0usize..125,
+        ) {
+            use super::*;
+
+            let margin = margin.min((tile_height.min(tile_width) - 1) / 2);
+            let (height, width) = (tile_height + extra_height, tile_width + extra_width);
+            let batcher = Batcher::new(height, width, (tile_height, tile_width), margin).unwrap();
+            let slices = batcher.iter().map(deref_slice_info).collect::<Vec<_>>();
+            prop_assert_eq!(slices.len(), batcher.num_batches());
+
+            for (tiles, len) in [(batcher.rows, height), (batcher.columns, width)] {
+                let ranges = (0..tiles.count).map(|i| tiles.range(i)).collect::<Vec<_>>();
+
+                for range in &ranges {
+                    prop_assert!(range.end <= len);
+                }
+                for pixel in 0..len {
+                    // the pixel is far enough from the inner edges of some tile covering it
+                    let first = ranges.partition_point(|range| range.end <= pixel);
+                    let mut covering = ranges[first..]
+                        .iter()
+                        .take_while(|range| range.start <= pixel);
+                    let is_inside = covering.any(|range| {
+                        (range.start == 0 || pixel >= range.start + margin)
+                            && (range.end == len || pixel + margin < range.end)
+                    });
+                    prop_assert!(is_inside, "pixel {} of {:?}", pixel, ranges);
+                }
+            }
+        }
+    }
 }
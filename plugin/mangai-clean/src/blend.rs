@@ -0,0 +1,220 @@
+use crate::options::BlendMode;
+use ndarray::{s, Array2, ArrayView2, Zip};
+
+/// Accumulates per-batch model outputs into a single page-wide probability map
+pub struct ProbabilityAccumulator {
+    mode: BlendMode,
+    page_height: usize,
+    /// Page row of the first row of `values`, see [`Self::take_rows`]
+    top: usize,
+    values: Array2<f32>,
+    weights: Array2<f32>,
+}
+
+impl ProbabilityAccumulator {
+    pub fn new(height: usize, width: usize, mode: BlendMode) -> Self {
+        let mut accumulator = Self::new_streaming(height, width, mode);
+        accumulator.grow(height);
+        accumulator
+    }
+
+    /// Keeps only the rows which are still being accumulated, allocating them as the batches come
+    ///
+    /// The finished rows are taken out with [`Self::take_rows`]
+    pub fn new_streaming(height: usize, width: usize, mode: BlendMode) -> Self {
+        Self {
+            mode,
+            page_height: height,
+            top: 0,
+            values: Array2::zeros((0, width)),
+            weights: Array2::zeros((0, width)),
+        }
+    }
+
+    fn initial_value(&self) -> f32 {
+        match self.mode {
+            BlendMode::Or | BlendMode::Max => f32::NEG_INFINITY,
+            BlendMode::Average | BlendMode::EdgeWeighted => 0.0,
+        }
+    }
+
+    /// Makes sure the rows up to the page row `bottom` are allocated
+    fn grow(&mut self, bottom: usize) {
+        let (rows, width) = self.values.dim();
+        if self.top + rows >= bottom {
+            return;
+        }
+
+        let new_rows = bottom - self.top;
+        let mut values = Array2::from_elem((new_rows, width), self.initial_value());
+        let mut weights = Array2::zeros((new_rows, width));
+        values.slice_mut(s![..rows, ..]).assign(&self.values);
+        weights.slice_mut(s![..rows, ..]).assign(&self.weights);
+        self.values = values;
+        self.weights = weights;
+    }
+
+    /// Weight of each pixel in a batch, growing linearly with the distance to the batch edges
+    ///
+    /// Edges that lie on the page border are not taken into account: there is no context to miss there anyway
+    fn edge_weights(&self, origin: (usize, usize), dim: (usize, usize)) -> Array2<f32> {
+        let page_height = self.page_height;
+        let page_width = self.values.dim().1;
+        let (top, left) = origin;
+        let (height, width) = dim;
+
+        let top_is_border = top == 0;
+        let left_is_border = left == 0;
+        let bottom_is_border = top + height == page_height;
+        let right_is_border = left + width == page_width;
+
+        Array2::from_shape_fn(dim, |(y, x)| {
+            let mut distance = usize::MAX;
+            if !top_is_border {
+                distance = distance.min(y);
+            }
+            if !bottom_is_border {
+                distance = distance.min(height - 1 - y);
+            }
+            if !left_is_border {
+                distance = distance.min(x);
+            }
+            if !right_is_border {
+                distance = distance.min(width - 1 - x);
+            }
+
+            if distance == usize::MAX {
+                1.0
+            } else {
+                // +1 so that the pixels right on the edge still contribute something
+                (distance + 1) as f32
+            }
+        })
+    }
+
+    /// Adds the output of a batch located at `origin` (top, left) on the page
+    pub fn add(&mut self, origin: (usize, usize), probabilities: ArrayView2<f32>) {
+        let (top, left) = origin;
+        let (height, width) = probabilities.dim();
+        self.grow(top + height);
+        let slice = s![top - self.top..top - self.top + height, left..left + width];
+
+        match self.mode {
+            BlendMode::Or | BlendMode::Max => {
+                Zip::from(self.values.slice_mut(slice))
+                    .and(&probabilities)
+                    .for_each(|a, &b| *a = a.max(b));
+            }
+            BlendMode::Average => {
+                Zip::from(self.values.slice_mut(slice))
+                    .and(self.weights.slice_mut(slice))
+                    .and(&probabilities)
+                    .for_each(|a, w, &b| {
+                        *a += b;
+                        *w += 1.0;
+                    });
+            }
+            BlendMode::EdgeWeighted => {
+                let edge_weights = self.edge_weights(origin, (height, width));
+                Zip::from(self.values.slice_mut(slice))
+                    .and(self.weights.slice_mut(slice))
+                    .and(&probabilities)
+                    .and(&edge_weights)
+                    .for_each(|a, w, &b, &ew| {
+                        *a += b * ew;
+                        *w += ew;
+                    });
+            }
+        }
+    }
+
+    /// Removes the rows above the page row `bottom`, returning their merged values
+    ///
+    /// No batch added afterwards may cover these rows
+    pub fn take_rows(&mut self, bottom: usize) -> Array2<f32> {
+        self.grow(bottom);
+        let count = bottom - self.top;
+        let values = self.values.slice(s![..count, ..]).to_owned();
+        let weights = self.weights.slice(s![..count, ..]).to_owned();
+        self.values = self.values.slice(s![count.., ..]).to_owned();
+        self.weights = self.weights.slice(s![count.., ..]).to_owned();
+        self.top = bottom;
+
+        Self::normalize(self.mode, values, &weights)
+    }
+
+    pub fn finish(self) -> Array2<f32> {
+        Self::normalize(self.mode, self.values, &self.weights)
+    }
+
+    fn normalize(mode: BlendMode, mut values: Array2<f32>, weights: &Array2<f32>) -> Array2<f32> {
+        match mode {
+            BlendMode::Or | BlendMode::Max => values,
+            BlendMode::Average | BlendMode::EdgeWeighted => {
+                Zip::from(&mut values).and(weights).for_each(|a, &w| {
+                    if w > 0.0 {
+                        *a /= w;
+                    }
+                });
+                values
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ndarray::arr2;
+
+    #[test]
+    fn test_average() {
+        let mut acc = ProbabilityAccumulator::new(1, 3, BlendMode::Average);
+        acc.add((0, 0), arr2(&[[1.0, 1.0]]).view());
+        acc.add((0, 1), arr2(&[[0.0, 0.5]]).view());
+        assert_eq!(acc.finish(), arr2(&[[1.0, 0.5, 0.5]]));
+    }
+
+    #[test]
+    fn test_max() {
+        let mut acc = ProbabilityAccumulator::new(1, 3, BlendMode::Max);
+        acc.add((0, 0), arr2(&[[1.0, 0.0]]).view());
+        acc.add((0, 1), arr2(&[[0.5, 0.5]]).view());
+        assert_eq!(acc.finish(), arr2(&[[1.0, 0.5, 0.5]]));
+    }
+
+    #[test]
+    fn test_edge_weighted() {
+        let mut acc = ProbabilityAccumulator::new(1, 4, BlendMode::EdgeWeighted);
+        // only the right edge of the first batch is inside the page
+        acc.add((0, 0), arr2(&[[0.0, 0.0, 0.0]]).view());
+        // only the left edge of the second batch is inside the page
+        acc.add((0, 1), arr2(&[[1.0, 1.0, 1.0]]).view());
+
+        let result = acc.finish();
+        assert_eq!(result[[0, 0]], 0.0);
+        assert_eq!(result[[0, 3]], 1.0);
+        // the batch which is further from its inner edge wins
+        assert_eq!(result[[0, 1]], 1.0 / 3.0);
+        assert_eq!(result[[0, 2]], 2.0 / 3.0);
+    }
+
+    #[test]
+    fn test_streaming() {
+        let batch = |value: f32| Array2::from_elem((2, 2), value);
+        let mut whole = ProbabilityAccumulator::new(3, 2, BlendMode::EdgeWeighted);
+        let mut streaming = ProbabilityAccumulator::new_streaming(3, 2, BlendMode::EdgeWeighted);
+        for acc in [&mut whole, &mut streaming] {
+            acc.add((0, 0), batch(0.0).view());
+        }
+
+        let mut rows = streaming.take_rows(1);
+        for acc in [&mut whole, &mut streaming] {
+            acc.add((1, 0), batch(1.0).view());
+        }
+        rows.append(ndarray::Axis(0), streaming.finish().view())
+            .unwrap();
+
+        assert_eq!(rows, whole.finish());
+    }
+}
@@ -0,0 +1,243 @@
+use crate::options::BubbleOptions;
+use crate::sample::Sample;
+use ndarray::{s, Array2, ArrayView2, ArrayView3, Axis, Zip};
+
+/// Pixels of the filled bubble: the `region` of light pixels, and everything it encloses (like the text)
+///
+/// Returns the top left corner of the returned mask on the page. The region must not touch the page border
+fn fill_bubble(region: &[(usize, usize)]) -> ((usize, usize), Array2<bool>) {
+    let top = region.iter().map(|&(y, _)| y).min().unwrap() - 1;
+    let left = region.iter().map(|&(_, x)| x).min().unwrap() - 1;
+    let bottom = region.iter().map(|&(y, _)| y).max().unwrap() + 2;
+    let right = region.iter().map(|&(_, x)| x).max().unwrap() + 2;
+    let (height, width) = (bottom - top, right - left);
+
+    let mut filled = Array2::from_elem((height, width), true);
+    for &(y, x) in region {
+        filled[[y - top, x - left]] = false;
+    }
+
+    // whatever can be reached from outside of the region isn't a part of the bubble
+    let mut reached = Array2::from_elem((height, width), false);
+    let mut stack = (0..height)
+        .flat_map(|y| [(y, 0), (y, width - 1)])
+        .chain((0..width).flat_map(|x| [(0, x), (height - 1, x)]))
+        .collect::<Vec<_>>();
+    while let Some((y, x)) = stack.pop() {
+        if y >= height || x >= width || reached[[y, x]] || !filled[[y, x]] {
+            continue;
+        }
+        reached[[y, x]] = true;
+        stack.extend([
+            (y.wrapping_sub(1), x),
+            (y + 1, x),
+            (y, x.wrapping_sub(1)),
+            (y, x + 1),
+        ]);
+    }
+
+    filled &= &!&reached;
+    for &(y, x) in region {
+        filled[[y - top, x - left]] = true;
+    }
+    ((top, left), filled)
+}
+
+/// Grows the text regions of the mask to the speech bubbles enclosing them
+///
+/// A bubble is found by flood filling (4-connected) the light pixels around the text, so the dark outline stops the fill
+/// even where the dilated mask covers it. If the fill reaches the page border or grows too large compared to the text
+/// inside, there is no closed bubble, and the mask is left as is. Otherwise the whole bubble goes to the mask, and the
+/// outline is taken out of it: the dark pixels outside of the bubble which are in the mask, but not in `text`
+///
+/// `text` is the mask before the dilation, or anything between it and the dilated `mask`
+pub fn expand_to_bubbles<T: Sample>(
+    image: ArrayView3<T>,
+    mask: &mut Array2<bool>,
+    text: ArrayView2<bool>,
+    options: &BubbleOptions,
+) {
+    let (height, width) = mask.dim();
+    let channels = image.dim().0 as f32;
+    let light = image
+        .map_axis(Axis(0), |pixel| {
+            pixel.iter().map(|&v| v.to_u8_scale()).sum::<f32>() / channels
+        })
+        .mapv(|luma| luma >= options.light_threshold as f32);
+
+    let neighbours = |(y, x): (usize, usize)| {
+        [
+            (y.wrapping_sub(1), x),
+            (y + 1, x),
+            (y, x.wrapping_sub(1)),
+            (y, x + 1),
+        ]
+        .into_iter()
+        .filter(|&(y, x)| y < height && x < width)
+    };
+
+    let original = mask.clone();
+    let mut in_bubble = Array2::from_elem((height, width), false);
+    let mut outline = Array2::from_elem((height, width), false);
+    let mut visited = Array2::from_elem((height, width), false);
+    let mut stack = Vec::new();
+    let mut region = Vec::new();
+
+    for y in 0..height {
+        for x in 0..width {
+            let near_text = original[[y, x]] || neighbours((y, x)).any(|p| original[p]);
+            if !light[[y, x]] || visited[[y, x]] || !near_text {
+                continue;
+            }
+
+            let mut touches_border = false;
+            region.clear();
+            visited[[y, x]] = true;
+            stack.push((y, x));
+            while let Some((y, x)) = stack.pop() {
+                region.push((y, x));
+                if y == 0 || x == 0 || y + 1 == height || x + 1 == width {
+                    touches_border = true;
+                }
+                for p in neighbours((y, x)) {
+                    if light[p] && !visited[p] {
+                        visited[p] = true;
+                        stack.push(p);
+                    }
+                }
+            }
+            if touches_border {
+                continue;
+            }
+
+            let ((top, left), filled) = fill_bubble(&region);
+            let window = s![top..top + filled.nrows(), left..left + filled.ncols()];
+            let text_area = Zip::from(&filled)
+                .and(original.slice(window))
+                .fold(0usize, |area, &filled, &masked| {
+                    area + (filled && masked) as usize
+                });
+            let area = filled.iter().filter(|&&filled| filled).count();
+            if area as f32 > text_area as f32 * options.max_growth {
+                continue;
+            }
+
+            let mut bubble = in_bubble.slice_mut(window);
+            bubble |= &filled;
+            // the outline pixels the dilation has reached, they go around the bubble from its edge
+            let is_outline = |p: (usize, usize)| {
+                let (y, x) = p;
+                let inside = y >= top
+                    && x >= left
+                    && y - top < filled.nrows()
+                    && x - left < filled.ncols()
+                    && filled[[y - top, x - left]];
+                !inside && !light[p] && original[p] && !text[p]
+            };
+            for &p in &region {
+                for p in neighbours(p) {
+                    if is_outline(p) && !outline[p] {
+                        outline[p] = true;
+                        stack.push(p);
+                    }
+                }
+            }
+            while let Some(p) = stack.pop() {
+                for p in neighbours(p) {
+                    if is_outline(p) && !outline[p] {
+                        outline[p] = true;
+                        stack.push(p);
+                    }
+                }
+            }
+        }
+    }
+
+    Zip::from(mask)
+        .and(&in_bubble)
+        .and(&outline)
+        .for_each(|masked, &in_bubble, &outline| {
+            *masked = in_bubble || (*masked && !outline);
+        });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ndarray::{s, Array3};
+
+    /// White page with a square bubble (outline at 5 and 24), dark text in the middle and grey halo around it
+    fn page() -> (Array3<u8>, Array2<bool>) {
+        let mut image = Array3::from_elem((1, 30, 30), 255u8);
+        image.slice_mut(s![.., 5..25, 5..25]).fill(0);
+        image.slice_mut(s![.., 6..24, 6..24]).fill(250);
+        image.slice_mut(s![.., 12..18, 12..18]).fill(220);
+        image.slice_mut(s![.., 13..17, 13..17]).fill(0);
+
+        let mut mask = Array2::from_elem((30, 30), false);
+        mask.slice_mut(s![13..17, 13..17]).fill(true);
+
+        (image, mask)
+    }
+
+    fn options() -> BubbleOptions {
+        BubbleOptions {
+            light_threshold: 200,
+            max_growth: 30.0,
+        }
+    }
+
+    #[test]
+    fn test_closed_bubble() {
+        let (image, mut mask) = page();
+        let text = mask.clone();
+        expand_to_bubbles(image.view(), &mut mask, text.view(), &options());
+
+        let mut expected = Array2::from_elem((30, 30), false);
+        expected.slice_mut(s![6..24, 6..24]).fill(true);
+        assert_eq!(mask, expected);
+    }
+
+    #[test]
+    fn test_open_bubble() {
+        let (mut image, mask) = page();
+        // a gap in the outline lets the fill out to the page
+        image.slice_mut(s![.., 5, 10..12]).fill(255);
+
+        let mut expanded = mask.clone();
+        expand_to_bubbles(image.view(), &mut expanded, mask.view(), &options());
+        assert_eq!(expanded, mask);
+    }
+
+    #[test]
+    fn test_too_large_bubble() {
+        let (image, mask) = page();
+
+        let mut expanded = mask.clone();
+        let options = BubbleOptions {
+            max_growth: 10.0,
+            ..options()
+        };
+        expand_to_bubbles(image.view(), &mut expanded, mask.view(), &options);
+        assert_eq!(expanded, mask);
+    }
+
+    #[test]
+    fn test_text_touching_outline() {
+        let (mut image, _) = page();
+        image.slice_mut(s![.., 12..18, 12..18]).fill(250);
+        image.slice_mut(s![.., 13..17, 13..17]).fill(250);
+        image.slice_mut(s![.., 6..10, 13..17]).fill(0);
+        let mut text = Array2::from_elem((30, 30), false);
+        text.slice_mut(s![6..10, 13..17]).fill(true);
+        // the dilation covers the outline, and gets out of the bubble
+        let mut mask = Array2::from_elem((30, 30), false);
+        mask.slice_mut(s![4..12, 11..19]).fill(true);
+
+        expand_to_bubbles(image.view(), &mut mask, text.view(), &options());
+
+        assert!(mask.slice(s![6..24, 6..24]).iter().all(|&masked| masked));
+        let outline = image.index_axis(Axis(0), 0).mapv(|value| value == 0) & !&text;
+        assert!(!(outline & &mask).iter().any(|&masked| masked));
+    }
+}
@@ -0,0 +1,187 @@
+use ndarray::{Array2, ArrayView2};
+
+/// A connected component of a mask
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Component {
+    /// Label of the component in [`Labeling::labels`]
+    pub label: u32,
+    pub top: usize,
+    pub left: usize,
+    /// Exclusive
+    pub bottom: usize,
+    /// Exclusive
+    pub right: usize,
+    /// Number of pixels in the component
+    pub area: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Labeling {
+    /// `0` for the pixels outside of the mask, otherwise `component index + 1`
+    pub labels: Array2<u32>,
+    pub components: Vec<Component>,
+}
+
+fn find(parents: &mut [u32], mut x: u32) -> u32 {
+    while parents[x as usize] != x {
+        // path halving
+        parents[x as usize] = parents[parents[x as usize] as usize];
+        x = parents[x as usize];
+    }
+    x
+}
+
+fn union(parents: &mut [u32], a: u32, b: u32) {
+    let a = find(parents, a);
+    let b = find(parents, b);
+    if a < b {
+        parents[b as usize] = a;
+    } else {
+        parents[a as usize] = b;
+    }
+}
+
+/// Labels the 8-connected components of the mask (classic two-pass algorithm)
+pub fn label_components(mask: ArrayView2<bool>) -> Labeling {
+    let (height, width) = mask.dim();
+    let mut labels = Array2::<u32>::zeros((height, width));
+    // parents[0] is the background
+    let mut parents = vec![0u32];
+
+    for y in 0..height {
+        for x in 0..width {
+            if !mask[[y, x]] {
+                continue;
+            }
+
+            // already visited neighbours: W, NW, N, NE
+            let mut neighbours = [0u32; 4];
+            if x > 0 {
+                neighbours[0] = labels[[y, x - 1]];
+            }
+            if y > 0 {
+                if x > 0 {
+                    neighbours[1] = labels[[y - 1, x - 1]];
+                }
+                neighbours[2] = labels[[y - 1, x]];
+                if x + 1 < width {
+                    neighbours[3] = labels[[y - 1, x + 1]];
+                }
+            }
+
+            let label = match neighbours.iter().copied().filter(|&l| l != 0).min() {
+                Some(label) => {
+                    for &neighbour in neighbours.iter().filter(|&&l| l != 0) {
+                        union(&mut parents, label, neighbour);
+                    }
+                    label
+                }
+                None => {
+                    let label = parents.len() as u32;
+                    parents.push(label);
+                    label
+                }
+            };
+            labels[[y, x]] = label;
+        }
+    }
+
+    // make the labels contiguous
+    let mut remap = vec![0u32; parents.len()];
+    let mut components = Vec::<Component>::new();
+    for provisional in 1..parents.len() as u32 {
+        let root = find(&mut parents, provisional);
+        if root == provisional {
+            components.push(Component {
+                label: components.len() as u32 + 1,
+                top: usize::MAX,
+                left: usize::MAX,
+                bottom: 0,
+                right: 0,
+                area: 0,
+            });
+            remap[provisional as usize] = components.len() as u32;
+        } else {
+            // roots always have smaller labels, so they are already remapped
+            remap[provisional as usize] = remap[root as usize];
+        }
+    }
+
+    for ((y, x), label) in labels.indexed_iter_mut() {
+        if *label == 0 {
+            continue;
+        }
+        *label = remap[*label as usize];
+
+        let component = &mut components[*label as usize - 1];
+        component.top = component.top.min(y);
+        component.left = component.left.min(x);
+        component.bottom = component.bottom.max(y + 1);
+        component.right = component.right.max(x + 1);
+        component.area += 1;
+    }
+
+    Labeling { labels, components }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ndarray::arr2;
+
+    #[test]
+    fn test_label_components() {
+        let mask = arr2(&[
+            [true, false, false, true],
+            [false, true, false, true],
+            [false, false, false, true],
+            [true, true, false, false],
+        ]);
+        let labeling = label_components(mask.view());
+
+        assert_eq!(
+            labeling.labels,
+            arr2(&[[1, 0, 0, 2], [0, 1, 0, 2], [0, 0, 0, 2], [3, 3, 0, 0]])
+        );
+        assert_eq!(
+            labeling.components,
+            vec![
+                Component {
+                    label: 1,
+                    top: 0,
+                    left: 0,
+                    bottom: 2,
+                    right: 2,
+                    area: 2
+                },
+                Component {
+                    label: 2,
+                    top: 0,
+                    left: 3,
+                    bottom: 3,
+                    right: 4,
+                    area: 3
+                },
+                Component {
+                    label: 3,
+                    top: 3,
+                    left: 0,
+                    bottom: 4,
+                    right: 2,
+                    area: 2
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_label_u_shape() {
+        // the two arms get different provisional labels and are merged at the bottom
+        let mask = arr2(&[[true, false, true], [true, false, true], [true, true, true]]);
+        let labeling = label_components(mask.view());
+
+        assert_eq!(labeling.components.len(), 1);
+        assert_eq!(labeling.components[0].area, 7);
+        assert!(labeling.labels.iter().all(|&l| l <= 1));
+    }
+}
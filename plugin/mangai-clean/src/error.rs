@@ -0,0 +1,52 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum MangaiError {
+    #[error("shape mismatch in {what}: expected {expected:?}, got {actual:?}")]
+    ShapeMismatch {
+        what: &'static str,
+        expected: Vec<usize>,
+        actual: Vec<usize>,
+    },
+    #[error("unsupported number of channels: {channels}")]
+    UnsupportedChannels { channels: usize },
+    #[error("model inference failed")]
+    Inference(#[source] anyhow::Error),
+    #[error("failed to load the model")]
+    ModelLoad(#[source] anyhow::Error),
+    #[error("operation was cancelled")]
+    Cancelled,
+    #[error("invalid page scale {scale}, it must be finite and positive")]
+    InvalidScale { scale: f32 },
+    #[error("the page scaled to {width}x{height} would be too large")]
+    ScaledPageTooLarge { height: usize, width: usize },
+    #[error("tile margin of {margin} pixels must be less than half of the {}x{} tiles", tile_size.1, tile_size.0)]
+    TileMarginTooLarge {
+        margin: usize,
+        tile_size: (usize, usize),
+    },
+    #[error("{option} is not supported by {operation}")]
+    UnsupportedOption {
+        option: &'static str,
+        operation: &'static str,
+    },
+}
+
+pub type Result<T, E = MangaiError> = std::result::Result<T, E>;
+
+impl MangaiError {
+    pub(crate) fn check_shape(
+        what: &'static str,
+        expected: &[usize],
+        actual: &[usize],
+    ) -> Result<()> {
+        if expected != actual {
+            return Err(MangaiError::ShapeMismatch {
+                what,
+                expected: expected.to_vec(),
+                actual: actual.to_vec(),
+            });
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+use crate::components::{label_components, Component};
+use crate::sample::Sample;
+use ndarray::{s, Array2, ArrayView2, ArrayView3, ArrayViewMut3, Zip};
+
+/// Marks all the pixels within `radius` (in chessboard distance) from the masked ones
+pub(super) fn dilate_square(mask: ArrayView2<bool>, radius: usize) -> Array2<bool> {
+    let (height, width) = mask.dim();
+
+    let mut horizontal = Array2::from_elem((height, width), false);
+    for ((y, x), out) in horizontal.indexed_iter_mut() {
+        let from = x.saturating_sub(radius);
+        let to = (x + radius + 1).min(width);
+        *out = mask.slice(s![y, from..to]).iter().any(|&m| m);
+    }
+
+    let mut result = Array2::from_elem((height, width), false);
+    for ((y, x), out) in result.indexed_iter_mut() {
+        let from = y.saturating_sub(radius);
+        let to = (y + radius + 1).min(height);
+        *out = horizontal.slice(s![from..to, x]).iter().any(|&m| m);
+    }
+
+    result
+}
+
+/// Lower median of the values
+fn median<T: Sample>(values: &mut [T]) -> T {
+    let middle = (values.len() - 1) / 2;
+    *values.select_nth_unstable_by(middle, T::total_cmp).1
+}
+
+/// Fills a single region with the median color of the unmasked pixels within `ring_width` around it
+pub(super) fn fill_component<T: Sample>(
+    image_in: ArrayView3<T>,
+    mask: ArrayView2<bool>,
+    labels: ArrayView2<u32>,
+    component: &Component,
+    mut image_out: ArrayViewMut3<T>,
+    ring_width: usize,
+    fallback: T,
+) {
+    let (channels, height, width) = image_in.dim();
+
+    let top = component.top.saturating_sub(ring_width);
+    let left = component.left.saturating_sub(ring_width);
+    let bottom = (component.bottom + ring_width).min(height);
+    let right = (component.right + ring_width).min(width);
+    let window = s![top..bottom, left..right];
+
+    let region = labels.slice(window).mapv(|label| label == component.label);
+    let surroundings = dilate_square(region.view(), ring_width);
+
+    let mut samples = vec![Vec::new(); channels];
+    Zip::indexed(&surroundings)
+        .and(mask.slice(window))
+        .for_each(|(y, x), &near, &masked| {
+            if near && !masked {
+                for (c, samples) in samples.iter_mut().enumerate() {
+                    samples.push(image_in[[c, top + y, left + x]]);
+                }
+            }
+        });
+
+    let color = if samples[0].is_empty() {
+        vec![fallback; channels]
+    } else {
+        samples.iter_mut().map(|samples| median(samples)).collect()
+    };
+
+    Zip::indexed(&region).for_each(|(y, x), &inside| {
+        if inside {
+            for (c, &value) in color.iter().enumerate() {
+                image_out[[c, top + y, left + x]] = value;
+            }
+        }
+    });
+}
+
+/// Fills each connected region of the mask with the median color of the unmasked pixels surrounding it
+///
+/// Regions without any unmasked pixels in `ring_width` around them are filled with `fallback`
+pub fn fill<T: Sample>(
+    image_in: ArrayView3<T>,
+    mask: ArrayView2<bool>,
+    mut image_out: ArrayViewMut3<T>,
+    ring_width: usize,
+    fallback: T,
+) {
+    let labeling = label_components(mask);
+
+    for component in &labeling.components {
+        fill_component(
+            image_in,
+            mask,
+            labeling.labels.view(),
+            component,
+            image_out.view_mut(),
+            ring_width,
+            fallback,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ndarray::{arr2, Array3};
+
+    #[test]
+    fn test_dilate_square() {
+        let mask = arr2(&[
+            [false, false, false, false],
+            [false, true, false, false],
+            [false, false, false, false],
+            [false, false, false, false],
+        ]);
+        assert_eq!(
+            dilate_square(mask.view(), 1),
+            arr2(&[
+                [true, true, true, false],
+                [true, true, true, false],
+                [true, true, true, false],
+                [false, false, false, false],
+            ])
+        );
+    }
+
+    #[test]
+    fn test_median() {
+        assert_eq!(median(&mut [3u8, 1, 2, 5]), 2);
+        assert_eq!(median(&mut [0.5, f32::NAN, 0.25, 0.75, 1.0]), 0.75);
+    }
+
+    #[test]
+    fn test_fill_with_surroundings() {
+        // grey paper with a dark blob in the middle and a lighter column on the right
+        let mut image = Array3::from_elem((1, 7, 7), 200u8);
+        image.slice_mut(s![.., 2..5, 2..5]).fill(0);
+        image.slice_mut(s![.., .., 6]).fill(250);
+        let mut mask = Array2::from_elem((7, 7), false);
+        mask.slice_mut(s![2..5, 2..5]).fill(true);
+
+        let mut out = image.clone();
+        fill(image.view(), mask.view(), out.view_mut(), 1, 255);
+
+        assert!(out.slice(s![.., 2..5, 2..5]).iter().all(|&v| v == 200));
+        assert_eq!(out.slice(s![.., .., 6]), image.slice(s![.., .., 6]));
+    }
+}
@@ -0,0 +1,72 @@
+use crate::options::FillMode;
+use crate::sample::Sample;
+use crate::CleanOptions;
+use ndarray::{ArrayView2, ArrayView3, ArrayViewMut3, Zip};
+
+mod background;
+mod patchmatch;
+mod screentone;
+mod telea;
+
+/// How far around the light text on dark background to look for its background color
+const INVERTED_RING_WIDTH: usize = 3;
+
+/// Writes `image_in` to `image_out`, replacing the pixels under the mask according to [`CleanOptions::fill_mode`]
+///
+/// Images are in (channels, height, width) layout
+pub fn fill_page<T: Sample>(
+    image_in: ArrayView3<T>,
+    mask: ArrayView2<bool>,
+    mut image_out: ArrayViewMut3<T>,
+    options: &CleanOptions,
+) {
+    image_out.assign(&image_in);
+    let fill_value = T::from_u8_scale(options.fill_value as f32);
+
+    match options.fill_mode {
+        FillMode::Solid => {
+            // the mask is (height, width), so it can always be broadcast to the image
+            let mask = mask.broadcast(image_out.dim()).unwrap();
+            Zip::from(&mut image_out).and(mask).for_each(|out, &mask| {
+                if mask {
+                    *out = fill_value;
+                }
+            });
+        }
+        FillMode::Background { ring_width } => {
+            background::fill(image_in, mask, image_out, ring_width, fill_value)
+        }
+        FillMode::Telea { radius } => telea::fill(mask, image_out, radius, fill_value),
+        FillMode::PatchMatch {
+            patch_radius,
+            search_radius,
+        } => {
+            // PatchMatch only refines the fill, so start with something sensible
+            telea::fill(mask, image_out.view_mut(), patch_radius + 1, fill_value);
+            patchmatch::fill(mask, image_out, patch_radius, search_radius);
+        }
+        FillMode::Screentone {
+            ring_width,
+            max_period,
+        } => screentone::fill(
+            image_in, mask, image_out, ring_width, max_period, fill_value,
+        ),
+    }
+}
+
+/// Paints the light text on dark background under the mask with the color surrounding it
+///
+/// Unlike [`fill_page`], leaves the unmasked pixels of `image_out` alone
+pub fn fill_inverted<T: Sample>(
+    image_in: ArrayView3<T>,
+    mask: ArrayView2<bool>,
+    image_out: ArrayViewMut3<T>,
+) {
+    background::fill(
+        image_in,
+        mask,
+        image_out,
+        INVERTED_RING_WIDTH,
+        T::from_unit(0.0),
+    );
+}
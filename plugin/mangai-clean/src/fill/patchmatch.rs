@@ -0,0 +1,345 @@
+//! Exemplar-based inpainting: each masked pixel is replaced with the values from the most similar unmasked patches
+//! nearby, found with PatchMatch
+//!
+//! C. Barnes et al., "PatchMatch: A Randomized Correspondence Algorithm for Structural Image Editing", 2009
+//! Y. Wexler et al., "Space-Time Completion of Video", 2007
+
+use crate::components::{label_components, Component};
+use crate::sample::Sample;
+use ndarray::{s, Array2, Array3, ArrayView2, ArrayViewMut3};
+
+/// Number of rounds of nearest neighbour search followed by voting
+const ITERATIONS: usize = 5;
+/// Number of propagation & random search passes per round
+const PATCHMATCH_PASSES: usize = 2;
+
+/// Tiny xorshift generator, so that the results are reproducible without pulling in `rand`
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Uniform integer in `-radius..=radius`
+    fn offset(&mut self, radius: usize) -> isize {
+        (self.next() % (2 * radius as u64 + 1)) as isize - radius as isize
+    }
+}
+
+struct Region {
+    /// The window of the image around the region, in (channels, height, width) layout and 8-bit scale
+    image: Array3<f32>,
+    /// Whether a patch centered at the pixel lies inside the window and contains no masked pixels
+    valid_source: Array2<bool>,
+    /// Masked pixels of the region, which are the centers of the patches to be matched
+    targets: Vec<(usize, usize)>,
+    /// Index into `targets` or `usize::MAX`
+    target_index: Array2<usize>,
+    /// Center of the best source patch found so far for each target
+    nnf: Vec<(usize, usize)>,
+    costs: Vec<f32>,
+    patch_radius: usize,
+}
+
+impl Region {
+    fn patch_distance(&self, target: (usize, usize), source: (usize, usize), limit: f32) -> f32 {
+        let (channels, height, width) = self.image.dim();
+        let r = self.patch_radius as isize;
+        let mut distance = 0.0;
+
+        for dy in -r..=r {
+            let ty = target.0 as isize + dy;
+            if ty < 0 || ty as usize >= height {
+                continue;
+            }
+            let sy = (source.0 as isize + dy) as usize;
+            for dx in -r..=r {
+                let tx = target.1 as isize + dx;
+                if tx < 0 || tx as usize >= width {
+                    continue;
+                }
+                let sx = (source.1 as isize + dx) as usize;
+
+                for c in 0..channels {
+                    let d = self.image[[c, ty as usize, tx as usize]] - self.image[[c, sy, sx]];
+                    distance += d * d;
+                }
+            }
+            if distance >= limit {
+                // can't be better anyway
+                return distance;
+            }
+        }
+
+        distance
+    }
+
+    fn try_source(&mut self, i: usize, source: (isize, isize)) {
+        let (height, width) = self.valid_source.dim();
+        if source.0 < 0 || source.1 < 0 {
+            return;
+        }
+        let source = (source.0 as usize, source.1 as usize);
+        if source.0 >= height || source.1 >= width || !self.valid_source[source] {
+            return;
+        }
+
+        let cost = self.patch_distance(self.targets[i], source, self.costs[i]);
+        if cost < self.costs[i] {
+            self.costs[i] = cost;
+            self.nnf[i] = source;
+        }
+    }
+
+    fn patchmatch(&mut self, rng: &mut XorShift, reverse: bool) {
+        let (height, width) = self.valid_source.dim();
+        let max_radius = height.max(width);
+
+        for n in 0..self.targets.len() {
+            let i = if reverse {
+                self.targets.len() - 1 - n
+            } else {
+                n
+            };
+            let (y, x) = self.targets[i];
+            let step: isize = if reverse { 1 } else { -1 };
+
+            // propagation: try to continue the matches of the already visited neighbours
+            for (ny, nx) in [
+                (y as isize + step, x as isize),
+                (y as isize, x as isize + step),
+            ] {
+                if ny < 0 || nx < 0 || ny as usize >= height || nx as usize >= width {
+                    continue;
+                }
+                let neighbour = self.target_index[[ny as usize, nx as usize]];
+                if neighbour == usize::MAX {
+                    continue;
+                }
+                let (sy, sx) = self.nnf[neighbour];
+                self.try_source(
+                    i,
+                    (
+                        sy as isize - (ny - y as isize),
+                        sx as isize - (nx - x as isize),
+                    ),
+                );
+            }
+
+            // random search around the current match with exponentially decreasing radius
+            let mut radius = max_radius;
+            while radius >= 1 {
+                let (sy, sx) = self.nnf[i];
+                let candidate = (
+                    sy as isize + rng.offset(radius),
+                    sx as isize + rng.offset(radius),
+                );
+                self.try_source(i, candidate);
+                radius /= 2;
+            }
+        }
+    }
+
+    /// Replaces each target pixel with the average of the values suggested by the overlapping patches
+    fn vote(&mut self) {
+        let (channels, height, width) = self.image.dim();
+        let r = self.patch_radius as isize;
+
+        let mut sums = Array3::<f32>::zeros((channels, height, width));
+        let mut counts = Array2::<f32>::zeros((height, width));
+
+        for (&(ty, tx), &(sy, sx)) in self.targets.iter().zip(&self.nnf) {
+            for dy in -r..=r {
+                for dx in -r..=r {
+                    let (py, px) = (ty as isize + dy, tx as isize + dx);
+                    if py < 0 || px < 0 || py as usize >= height || px as usize >= width {
+                        continue;
+                    }
+                    let (py, px) = (py as usize, px as usize);
+                    if self.target_index[[py, px]] == usize::MAX {
+                        continue;
+                    }
+                    let (qy, qx) = ((sy as isize + dy) as usize, (sx as isize + dx) as usize);
+                    for c in 0..channels {
+                        sums[[c, py, px]] += self.image[[c, qy, qx]];
+                    }
+                    counts[[py, px]] += 1.0;
+                }
+            }
+        }
+
+        for &(y, x) in &self.targets {
+            for c in 0..channels {
+                self.image[[c, y, x]] = sums[[c, y, x]] / counts[[y, x]];
+            }
+        }
+    }
+}
+
+/// Finds the centers of the patches that can be used as sources
+fn valid_sources(masked: ArrayView2<bool>, patch_radius: usize) -> Array2<bool> {
+    let (height, width) = masked.dim();
+
+    // integral image of the masked pixels count
+    let mut integral = Array2::<usize>::zeros((height + 1, width + 1));
+    for y in 0..height {
+        for x in 0..width {
+            integral[[y + 1, x + 1]] =
+                masked[[y, x]] as usize + integral[[y, x + 1]] + integral[[y + 1, x]]
+                    - integral[[y, x]];
+        }
+    }
+
+    let r = patch_radius;
+    Array2::from_shape_fn((height, width), |(y, x)| {
+        if y < r || x < r || y + r >= height || x + r >= width {
+            return false;
+        }
+        let (top, left, bottom, right) = (y - r, x - r, y + r + 1, x + r + 1);
+        integral[[bottom, right]] + integral[[top, left]]
+            - integral[[top, right]]
+            - integral[[bottom, left]]
+            == 0
+    })
+}
+
+fn fill_component<T: Sample>(
+    component: &Component,
+    labels: ArrayView2<u32>,
+    mut image_out: ArrayViewMut3<T>,
+    patch_radius: usize,
+    search_radius: usize,
+) {
+    let (_, height, width) = image_out.dim();
+    let margin = search_radius + patch_radius;
+    let top = component.top.saturating_sub(margin);
+    let left = component.left.saturating_sub(margin);
+    let bottom = (component.bottom + margin).min(height);
+    let right = (component.right + margin).min(width);
+    let window = s![top..bottom, left..right];
+
+    let labels = labels.slice(window);
+    let valid_source = valid_sources(labels.mapv(|label| label != 0).view(), patch_radius);
+    let sources = valid_source
+        .indexed_iter()
+        .filter(|(_, &valid)| valid)
+        .map(|(position, _)| position)
+        .collect::<Vec<_>>();
+    if sources.is_empty() {
+        // nothing to copy from, keep whatever the initial fill was
+        return;
+    }
+
+    let mut target_index = Array2::from_elem(labels.dim(), usize::MAX);
+    let mut targets = Vec::new();
+    for (position, &label) in labels.indexed_iter() {
+        if label == component.label {
+            target_index[position] = targets.len();
+            targets.push(position);
+        }
+    }
+
+    let mut rng = XorShift(0x9E37_79B9_7F4A_7C15 ^ component.label as u64);
+    let nnf = targets
+        .iter()
+        .map(|_| sources[rng.next() as usize % sources.len()])
+        .collect::<Vec<_>>();
+
+    let mut region = Region {
+        image: image_out
+            .slice(s![.., top..bottom, left..right])
+            .mapv(Sample::to_u8_scale),
+        valid_source,
+        costs: vec![f32::INFINITY; targets.len()],
+        targets,
+        target_index,
+        nnf,
+        patch_radius,
+    };
+
+    for iteration in 0..ITERATIONS {
+        // the image has changed, so the old costs are stale
+        for i in 0..region.targets.len() {
+            region.costs[i] =
+                region.patch_distance(region.targets[i], region.nnf[i], f32::INFINITY);
+        }
+        for pass in 0..PATCHMATCH_PASSES {
+            region.patchmatch(&mut rng, (iteration + pass) % 2 == 1);
+        }
+        region.vote();
+    }
+
+    let mut out = image_out.slice_mut(s![.., top..bottom, left..right]);
+    for &(y, x) in &region.targets {
+        for c in 0..out.dim().0 {
+            out[[c, y, x]] = T::from_u8_scale(region.image[[c, y, x]]);
+        }
+    }
+}
+
+/// Synthesizes the contents of each connected region of the mask from the unmasked patches at most `search_radius`
+/// pixels away
+///
+/// `image_out` must already contain an initial guess for the masked pixels, which is refined iteratively
+pub fn fill<T: Sample>(
+    mask: ArrayView2<bool>,
+    mut image_out: ArrayViewMut3<T>,
+    patch_radius: usize,
+    search_radius: usize,
+) {
+    let labeling = label_components(mask);
+
+    for component in &labeling.components {
+        fill_component(
+            component,
+            labeling.labels.view(),
+            image_out.view_mut(),
+            patch_radius,
+            search_radius,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ndarray::Zip;
+
+    #[test]
+    fn test_valid_sources() {
+        let mut masked = Array2::from_elem((5, 6), false);
+        masked[[0, 5]] = true;
+        let valid = valid_sources(masked.view(), 1);
+
+        let expected = ndarray::arr2(&[
+            [false, false, false, false, false, false],
+            [false, true, true, true, false, false],
+            [false, true, true, true, true, false],
+            [false, true, true, true, true, false],
+            [false, false, false, false, false, false],
+        ]);
+        assert_eq!(valid, expected);
+    }
+
+    #[test]
+    fn test_fill_stripes() {
+        // vertical stripes, which a diffusion fill would turn into a flat grey
+        let image =
+            Array3::from_shape_fn((1, 30, 30), |(_, _, x)| if x % 4 < 2 { 0u8 } else { 255 });
+        let mut mask = Array2::from_elem((30, 30), false);
+        mask.slice_mut(s![10..20, 10..20]).fill(true);
+
+        let mut out = image.clone();
+        out.slice_mut(s![.., 10..20, 10..20]).fill(128);
+        fill(mask.view(), out.view_mut(), 2, 10);
+
+        let errors = Zip::from(&out)
+            .and(&image)
+            .fold(0, |acc, &a, &b| acc + (a != b) as usize);
+        assert!(errors < 10, "{} pixels differ", errors);
+    }
+}
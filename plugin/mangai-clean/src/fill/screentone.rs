@@ -0,0 +1,357 @@
+//! Re-synthesis of periodic halftone patterns (screentone) under the removed text
+
+use super::background;
+use crate::components::{label_components, Component};
+use crate::sample::Sample;
+use ndarray::{s, Array2, ArrayView2, ArrayView3, ArrayViewMut3, Axis, Zip};
+use std::collections::VecDeque;
+
+/// Minimal autocorrelation for a shift to be considered a period of the pattern
+const MIN_CORRELATION: f32 = 0.5;
+/// Surroundings flatter than this are just paper, not a tone
+const MIN_VARIANCE: f32 = 25.0;
+/// Peaks within this fraction of the strongest one are considered equally good, the shortest one is picked
+const PEAK_TOLERANCE: f32 = 0.9;
+/// How many periods away (along each lattice vector) a source pixel can be
+const MAX_PERIODS: isize = 3;
+
+/// The pattern repeats itself when shifted by any integer combination of the vectors, given as (dy, dx)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Lattice {
+    v1: (isize, isize),
+    /// `None` for one-dimensional patterns, like lines
+    v2: Option<(isize, isize)>,
+}
+
+impl Lattice {
+    /// Shifts which map the pattern to itself, shortest first
+    fn translations(&self) -> Vec<(isize, isize)> {
+        let b_range = if self.v2.is_some() {
+            -MAX_PERIODS..=MAX_PERIODS
+        } else {
+            0..=0
+        };
+        let (v2y, v2x) = self.v2.unwrap_or((0, 0));
+
+        let mut translations = Vec::new();
+        for a in -MAX_PERIODS..=MAX_PERIODS {
+            for b in b_range.clone() {
+                if a == 0 && b == 0 {
+                    continue;
+                }
+                translations.push((a * self.v1.0 + b * v2y, a * self.v1.1 + b * v2x));
+            }
+        }
+        translations.sort_by_key(|&(dy, dx)| dy * dy + dx * dx);
+        translations
+    }
+}
+
+/// Normalized autocorrelation of `luma` sampled at the `samples` pixels, for shifts up to `max_period`
+///
+/// Only pairs where the shifted pixel is `known` are taken into account. The result is indexed by
+/// `(dy + max_period, dx + max_period)`
+fn autocorrelation(
+    luma: ArrayView2<f32>,
+    samples: ArrayView2<bool>,
+    known: ArrayView2<bool>,
+    max_period: usize,
+) -> Option<Array2<f32>> {
+    let (height, width) = luma.dim();
+
+    let (count, sum) =
+        Zip::from(&luma)
+            .and(&samples)
+            .fold((0usize, 0f32), |(count, sum), &value, &sample| {
+                if sample {
+                    (count + 1, sum + value)
+                } else {
+                    (count, sum)
+                }
+            });
+    if count == 0 {
+        return None;
+    }
+    let mean = sum / count as f32;
+    let variance = Zip::from(&luma)
+        .and(&samples)
+        .fold(0f32, |acc, &value, &sample| {
+            if sample {
+                acc + (value - mean) * (value - mean)
+            } else {
+                acc
+            }
+        })
+        / count as f32;
+    if variance < MIN_VARIANCE {
+        return None;
+    }
+
+    let p = max_period as isize;
+    let size = 2 * max_period + 1;
+    let mut correlation = Array2::from_elem((size, size), f32::NEG_INFINITY);
+    for dy in 0..=p {
+        for dx in -p..=p {
+            // the autocorrelation is symmetric, so compute only a half of it
+            if dy == 0 && dx < 0 {
+                continue;
+            }
+
+            let mut pairs = 0usize;
+            let mut acc = 0f32;
+            for ((y, x), &sample) in samples.indexed_iter() {
+                let (qy, qx) = (y as isize + dy, x as isize + dx);
+                if !sample || qy < 0 || qx < 0 || qy as usize >= height || qx as usize >= width {
+                    continue;
+                }
+                let (qy, qx) = (qy as usize, qx as usize);
+                if !known[[qy, qx]] {
+                    continue;
+                }
+                acc += (luma[[y, x]] - mean) * (luma[[qy, qx]] - mean);
+                pairs += 1;
+            }
+
+            // too few pairs make the estimate meaningless
+            if pairs * 4 >= count {
+                let value = acc / (pairs as f32 * variance);
+                correlation[[(dy + p) as usize, (dx + p) as usize]] = value;
+                correlation[[(p - dy) as usize, (p - dx) as usize]] = value;
+            }
+        }
+    }
+
+    Some(correlation)
+}
+
+/// Finds the lattice of a periodic pattern from the peaks of its autocorrelation
+fn find_lattice(correlation: ArrayView2<f32>, max_period: usize) -> Option<Lattice> {
+    let p = max_period as isize;
+    let at = |dy: isize, dx: isize| -> f32 {
+        correlation
+            .get([(dy + p) as usize, (dx + p) as usize])
+            .copied()
+            .unwrap_or(f32::NEG_INFINITY)
+    };
+
+    // local maxima of the autocorrelation in one half-plane, far enough from the trivial peak at zero shift
+    let mut peaks = Vec::new();
+    for dy in 0..=p {
+        for dx in -p..=p {
+            if (dy == 0 && dx < 0) || (dy.abs() < 2 && dx.abs() < 2) {
+                continue;
+            }
+            let value = at(dy, dx);
+            if value < MIN_CORRELATION {
+                continue;
+            }
+            let is_maximum = (-1..=1)
+                .flat_map(|ny| (-1..=1).map(move |nx| (ny, nx)))
+                .filter(|&offset| offset != (0, 0))
+                .all(|(ny, nx)| at(dy + ny, dx + nx) <= value);
+            if is_maximum {
+                peaks.push(((dy, dx), value));
+            }
+        }
+    }
+
+    let best = peaks
+        .iter()
+        .map(|&(_, value)| value)
+        .fold(f32::NEG_INFINITY, f32::max);
+    let mut strong = peaks
+        .into_iter()
+        .filter(|&(_, value)| value >= best * PEAK_TOLERANCE)
+        .map(|(shift, _)| shift)
+        .collect::<Vec<_>>();
+    strong.sort_by_key(|&(dy, dx)| dy * dy + dx * dx);
+
+    let v1 = *strong.first()?;
+    let v2 = strong
+        .iter()
+        .copied()
+        .find(|&(dy, dx)| v1.0 * dx - v1.1 * dy != 0);
+
+    Some(Lattice { v1, v2 })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn fill_component<T: Sample>(
+    image_in: ArrayView3<T>,
+    mask: ArrayView2<bool>,
+    labels: ArrayView2<u32>,
+    component: &Component,
+    mut image_out: ArrayViewMut3<T>,
+    ring_width: usize,
+    max_period: usize,
+    fallback: T,
+) {
+    // start with a flat fill, so that the pixels the pattern can't reach still look reasonable
+    background::fill_component(
+        image_in,
+        mask,
+        labels,
+        component,
+        image_out.view_mut(),
+        ring_width,
+        fallback,
+    );
+
+    let (_, height, width) = image_in.dim();
+    let margin = ring_width + max_period;
+    let top = component.top.saturating_sub(margin);
+    let left = component.left.saturating_sub(margin);
+    let bottom = (component.bottom + margin).min(height);
+    let right = (component.right + margin).min(width);
+    let window = s![top..bottom, left..right];
+
+    let labels = labels.slice(window);
+    let region = labels.mapv(|label| label == component.label);
+    let known = labels.mapv(|label| label == 0);
+    let samples = background::dilate_square(region.view(), ring_width) & &known;
+    let luma = image_in
+        .slice(s![.., top..bottom, left..right])
+        .mapv(Sample::to_u8_scale)
+        .mean_axis(Axis(0))
+        .unwrap();
+
+    let lattice = match autocorrelation(luma.view(), samples.view(), known.view(), max_period)
+        .and_then(|correlation| find_lattice(correlation.view(), max_period))
+    {
+        Some(lattice) => lattice,
+        // not a tone, the flat fill is the best we can do
+        None => return,
+    };
+    let translations = lattice.translations();
+
+    // go from the region border inwards, so that the inner pixels can copy the already synthesized ones
+    let (window_height, window_width) = region.dim();
+    let neighbours = |(y, x): (usize, usize)| {
+        [
+            (y.wrapping_sub(1), x),
+            (y + 1, x),
+            (y, x.wrapping_sub(1)),
+            (y, x + 1),
+        ]
+        .into_iter()
+        .filter(move |&(ny, nx)| ny < window_height && nx < window_width)
+    };
+    let mut queued = Array2::from_elem(region.dim(), false);
+    let mut queue = VecDeque::new();
+    for (position, &inside) in region.indexed_iter() {
+        if inside && neighbours(position).any(|neighbour| !region[neighbour]) {
+            queued[position] = true;
+            queue.push_back(position);
+        }
+    }
+
+    let mut available = known;
+    let mut out = image_out.slice_mut(s![.., top..bottom, left..right]);
+    while let Some((y, x)) = queue.pop_front() {
+        let source = translations.iter().find_map(|&(dy, dx)| {
+            let (sy, sx) = (y as isize + dy, x as isize + dx);
+            if sy < 0 || sx < 0 {
+                return None;
+            }
+            let (sy, sx) = (sy as usize, sx as usize);
+            (available.get([sy, sx]) == Some(&true)).then_some((sy, sx))
+        });
+
+        if let Some((sy, sx)) = source {
+            for c in 0..out.dim().0 {
+                out[[c, y, x]] = out[[c, sy, sx]];
+            }
+            available[[y, x]] = true;
+        }
+
+        for neighbour in neighbours((y, x)) {
+            if region[neighbour] && !queued[neighbour] {
+                queued[neighbour] = true;
+                queue.push_back(neighbour);
+            }
+        }
+    }
+}
+
+/// Continues the periodic pattern found within `ring_width` around each region of the mask into it
+///
+/// Patterns with a period longer than `max_period` are not detected. Regions without a detectable pattern around them
+/// get the same treatment as with [`background::fill`]
+pub fn fill<T: Sample>(
+    image_in: ArrayView3<T>,
+    mask: ArrayView2<bool>,
+    mut image_out: ArrayViewMut3<T>,
+    ring_width: usize,
+    max_period: usize,
+    fallback: T,
+) {
+    let labeling = label_components(mask);
+
+    for component in &labeling.components {
+        fill_component(
+            image_in,
+            mask,
+            labeling.labels.view(),
+            component,
+            image_out.view_mut(),
+            ring_width,
+            max_period,
+            fallback,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ndarray::Array3;
+
+    /// Dots on a square grid with the period of 6 pixels
+    fn dots(height: usize, width: usize) -> Array3<u8> {
+        Array3::from_shape_fn((1, height, width), |(_, y, x)| {
+            if y % 6 < 2 && x % 6 < 2 {
+                0
+            } else {
+                255
+            }
+        })
+    }
+
+    #[test]
+    fn test_find_lattice() {
+        let image = dots(40, 40);
+        let luma = image.index_axis(Axis(0), 0).mapv(|v| v as f32);
+        let samples = Array2::from_elem((40, 40), true);
+
+        let correlation = autocorrelation(luma.view(), samples.view(), samples.view(), 8).unwrap();
+        let lattice = find_lattice(correlation.view(), 8).unwrap();
+
+        assert_eq!(lattice.v1, (0, 6));
+        assert_eq!(lattice.v2, Some((6, 0)));
+    }
+
+    #[test]
+    fn test_fill_dots() {
+        let image = dots(60, 60);
+        let mut mask = Array2::from_elem((60, 60), false);
+        mask.slice_mut(s![20..40, 15..45]).fill(true);
+
+        let mut out = image.clone();
+        out.slice_mut(s![.., 20..40, 15..45]).fill(255);
+        fill(image.view(), mask.view(), out.view_mut(), 8, 10, 255);
+
+        assert_eq!(out, image);
+    }
+
+    #[test]
+    fn test_flat_paper() {
+        let image = Array3::from_elem((1, 30, 30), 230u8);
+        let mut mask = Array2::from_elem((30, 30), false);
+        mask.slice_mut(s![10..20, 10..20]).fill(true);
+
+        let mut out = image.clone();
+        fill(image.view(), mask.view(), out.view_mut(), 4, 10, 255);
+
+        assert!(out.iter().all(|&v| v == 230));
+    }
+}
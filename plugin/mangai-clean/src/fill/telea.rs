@@ -0,0 +1,317 @@
+//! Inpainting based on the fast marching method
+//!
+//! A. Telea, "An Image Inpainting Technique Based on the Fast Marching Method", 2004
+
+use crate::components::label_components;
+use crate::sample::Sample;
+use ndarray::{s, Array2, ArrayView2, ArrayViewMut3};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flag {
+    /// The value is known and final
+    Known,
+    /// The value is known, but the pixel is still on the front of the marching
+    Band,
+    /// The value is yet to be inpainted
+    Inside,
+    /// Masked, but belongs to some other region: neither used nor inpainted in this pass
+    Blocked,
+}
+
+/// Min-heap entry
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BandPixel {
+    t: f32,
+    y: usize,
+    x: usize,
+}
+
+impl Eq for BandPixel {}
+
+impl PartialOrd for BandPixel {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for BandPixel {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // reversed to make BinaryHeap a min-heap
+        other
+            .t
+            .total_cmp(&self.t)
+            .then_with(|| (other.y, other.x).cmp(&(self.y, self.x)))
+    }
+}
+
+struct Region<'a, T: Sample> {
+    /// The region window of the image, in (channels, height, width) layout
+    image: ArrayViewMut3<'a, T>,
+    flags: Array2<Flag>,
+    /// Distance to the initial boundary of the region
+    t: Array2<f32>,
+    radius: usize,
+}
+
+impl<'a, T: Sample> Region<'a, T> {
+    fn is_set(&self, y: isize, x: isize) -> bool {
+        let (height, width) = self.flags.dim();
+        y >= 0
+            && x >= 0
+            && (y as usize) < height
+            && (x as usize) < width
+            && matches!(
+                self.flags[[y as usize, x as usize]],
+                Flag::Known | Flag::Band
+            )
+    }
+
+    /// Solves the eikonal equation |grad T| = 1 using two neighbouring pixels
+    fn solve(&self, (y1, x1): (isize, isize), (y2, x2): (isize, isize)) -> f32 {
+        let t1 = self
+            .is_set(y1, x1)
+            .then(|| self.t[[y1 as usize, x1 as usize]]);
+        let t2 = self
+            .is_set(y2, x2)
+            .then(|| self.t[[y2 as usize, x2 as usize]]);
+
+        match (t1, t2) {
+            (Some(t1), Some(t2)) => {
+                let d = t1 - t2;
+                if d.abs() < 1.0 {
+                    0.5 * (t1 + t2 + (2.0 - d * d).sqrt())
+                } else {
+                    1.0 + t1.min(t2)
+                }
+            }
+            (Some(t), None) | (None, Some(t)) => 1.0 + t,
+            (None, None) => f32::INFINITY,
+        }
+    }
+
+    fn compute_t(&self, y: isize, x: isize) -> f32 {
+        [
+            self.solve((y - 1, x), (y, x - 1)),
+            self.solve((y + 1, x), (y, x - 1)),
+            self.solve((y - 1, x), (y, x + 1)),
+            self.solve((y + 1, x), (y, x + 1)),
+        ]
+        .into_iter()
+        .fold(f32::INFINITY, f32::min)
+    }
+
+    /// Gradient of T along one axis, using only the pixels with known T
+    fn t_gradient(&self, (y, x): (isize, isize), (dy, dx): (isize, isize)) -> f32 {
+        let t = self.t[[y as usize, x as usize]];
+        let next = self
+            .is_set(y + dy, x + dx)
+            .then(|| self.t[[(y + dy) as usize, (x + dx) as usize]]);
+        let prev = self
+            .is_set(y - dy, x - dx)
+            .then(|| self.t[[(y - dy) as usize, (x - dx) as usize]]);
+
+        match (prev, next) {
+            (Some(prev), Some(next)) => (next - prev) * 0.5,
+            (None, Some(next)) => next - t,
+            (Some(prev), None) => t - prev,
+            (None, None) => 0.0,
+        }
+    }
+
+    /// Computes the value of the pixel as a weighted average of the known pixels around it
+    fn inpaint(&mut self, y: isize, x: isize) {
+        let grad_y = self.t_gradient((y, x), (1, 0));
+        let grad_x = self.t_gradient((y, x), (0, 1));
+        let t = self.t[[y as usize, x as usize]];
+
+        let channels = self.image.dim().0;
+        let radius = self.radius as isize;
+        let mut sums = vec![0f32; channels];
+        let mut total_weight = 0f32;
+
+        for qy in y - radius..=y + radius {
+            for qx in x - radius..=x + radius {
+                let (ry, rx) = ((y - qy) as f32, (x - qx) as f32);
+                let len_sqr = ry * ry + rx * rx;
+                if len_sqr == 0.0 || len_sqr > (radius * radius) as f32 || !self.is_set(qy, qx) {
+                    continue;
+                }
+                let (qy, qx) = (qy as usize, qx as usize);
+
+                let dst = 1.0 / (len_sqr * len_sqr.sqrt());
+                let lev = 1.0 / (1.0 + (self.t[[qy, qx]] - t).abs());
+                let mut dir = ry * grad_y + rx * grad_x;
+                if dir.abs() <= 0.01 {
+                    dir = 1e-6;
+                }
+                let weight = (dst * lev * dir).abs();
+
+                for (c, sum) in sums.iter_mut().enumerate() {
+                    *sum += weight * self.image[[c, qy, qx]].to_unit();
+                }
+                total_weight += weight;
+            }
+        }
+
+        if total_weight > 0.0 {
+            for (c, sum) in sums.into_iter().enumerate() {
+                self.image[[c, y as usize, x as usize]] = T::from_unit(sum / total_weight);
+            }
+        }
+    }
+
+    fn run(&mut self) {
+        let (height, width) = self.flags.dim();
+
+        let mut heap = BinaryHeap::new();
+        for ((y, x), flag) in self.flags.indexed_iter() {
+            if *flag == Flag::Band {
+                heap.push(BandPixel { t: 0.0, y, x });
+            }
+        }
+
+        while let Some(BandPixel { y, x, .. }) = heap.pop() {
+            self.flags[[y, x]] = Flag::Known;
+
+            let neighbours = [
+                (y as isize - 1, x as isize),
+                (y as isize + 1, x as isize),
+                (y as isize, x as isize - 1),
+                (y as isize, x as isize + 1),
+            ];
+            for (ny, nx) in neighbours {
+                if ny < 0 || nx < 0 || ny as usize >= height || nx as usize >= width {
+                    continue;
+                }
+                if self.flags[[ny as usize, nx as usize]] != Flag::Inside {
+                    continue;
+                }
+
+                let t = self.compute_t(ny, nx);
+                self.t[[ny as usize, nx as usize]] = t;
+                self.inpaint(ny, nx);
+                self.flags[[ny as usize, nx as usize]] = Flag::Band;
+                heap.push(BandPixel {
+                    t,
+                    y: ny as usize,
+                    x: nx as usize,
+                });
+            }
+        }
+    }
+}
+
+/// Inpaints each connected region of the mask, looking at most `radius` pixels away
+///
+/// `image_out` must already contain the input image. Regions without any unmasked pixels around them are filled with
+/// `fallback`
+pub fn fill<T: Sample>(
+    mask: ArrayView2<bool>,
+    mut image_out: ArrayViewMut3<T>,
+    radius: usize,
+    fallback: T,
+) {
+    let (_, height, width) = image_out.dim();
+    let labeling = label_components(mask);
+    let margin = radius + 1;
+
+    for component in &labeling.components {
+        let top = component.top.saturating_sub(margin);
+        let left = component.left.saturating_sub(margin);
+        let bottom = (component.bottom + margin).min(height);
+        let right = (component.right + margin).min(width);
+
+        let labels = labeling.labels.slice(s![top..bottom, left..right]);
+        let mut flags = labels.mapv(|label| {
+            if label == component.label {
+                Flag::Inside
+            } else if label != 0 {
+                Flag::Blocked
+            } else {
+                Flag::Known
+            }
+        });
+        // the initial band is the known pixels bordering the region
+        for ((y, x), &label) in labels.indexed_iter() {
+            if label != 0 {
+                continue;
+            }
+            let touches_region = [
+                (y.wrapping_sub(1), x),
+                (y + 1, x),
+                (y, x.wrapping_sub(1)),
+                (y, x + 1),
+            ]
+            .into_iter()
+            .any(|(ny, nx)| labels.get([ny, nx]) == Some(&component.label));
+            if touches_region {
+                flags[[y, x]] = Flag::Band;
+            }
+        }
+
+        if !flags.iter().any(|&flag| flag == Flag::Band) {
+            // nothing to propagate from
+            let mut window = image_out.slice_mut(s![.., top..bottom, left..right]);
+            for ((_, y, x), value) in window.indexed_iter_mut() {
+                if labels[[y, x]] == component.label {
+                    *value = fallback;
+                }
+            }
+            continue;
+        }
+
+        let mut region = Region {
+            image: image_out.slice_mut(s![.., top..bottom, left..right]),
+            t: flags.mapv(|flag| {
+                if flag == Flag::Inside {
+                    f32::INFINITY
+                } else {
+                    0.0
+                }
+            }),
+            flags,
+            radius,
+        };
+        region.run();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ndarray::Array3;
+
+    #[test]
+    fn test_fill_gradient() {
+        // horizontal gradient with a hole in the middle
+        let image = Array3::from_shape_fn((1, 20, 20), |(_, _, x)| (x * 10) as u8);
+        let mut mask = Array2::from_elem((20, 20), false);
+        mask.slice_mut(s![6..14, 6..14]).fill(true);
+
+        let mut out = image.clone();
+        out.slice_mut(s![.., 6..14, 6..14]).fill(0);
+        fill(mask.view(), out.view_mut(), 5, 255);
+
+        for ((_, y, x), &value) in out.indexed_iter() {
+            let expected = image[[0, y, x]] as i32;
+            assert!(
+                (value as i32 - expected).abs() <= 20,
+                "pixel ({}, {}) = {}, expected about {}",
+                y,
+                x,
+                value,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_fill_without_context() {
+        let mask = Array2::from_elem((4, 4), true);
+        let mut out = Array3::<u8>::zeros((3, 4, 4));
+        fill(mask.view(), out.view_mut(), 5, 255);
+        assert!(out.iter().all(|&v| v == 255));
+    }
+}
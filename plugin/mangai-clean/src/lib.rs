@@ -1,16 +1,39 @@
-use crate::model::{BATCH_HEIGHT, BATCH_WIDTH, MODEL_INPUT_SHAPE, THRESHOLD};
-use anyhow::Result;
-use ndarray::{
-    s, Array2, Array3, ArrayView2, ArrayView3, ArrayViewMut2, CowArray, ShapeBuilder, SliceInfo,
-};
+use crate::blend::ProbabilityAccumulator;
+use crate::page::Layout;
+use crate::pipeline::{IncludedTiles, TileSource};
+use ndarray::{s, Array2, Array4, ArrayView2, ArrayView3, ArrayViewMut2, Axis};
 use ndarray::{ArrayViewMut3, Zip};
 use ndarray_vision::morphology::MorphologyExt;
-use std::ops::Deref;
 use tracing::info;
 
+mod augment;
 mod batcher;
+mod blend;
+mod bubbles;
+mod components;
+mod error;
+mod fill;
 mod model;
 mod model_registry;
+mod options;
+mod page;
+mod pipeline;
+mod postprocess;
+mod regions;
+mod resample;
+mod sample;
+mod streaming;
+mod strips;
+
+pub use error::{MangaiError, Result};
+pub use ndarray;
+pub use options::{
+    BlendMode, BubbleOptions, CleanOptions, FillMode, KernelShape, PostProcessingOptions,
+    Resolution,
+};
+pub use regions::TextRegion;
+pub use sample::Sample;
+pub use streaming::{PageSink, PageSource};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProgressKind {
@@ -22,191 +45,1064 @@ pub trait ProgressReporter {
     fn init(&mut self, kind: ProgressKind, operation: &str, total: usize);
     fn progress(&mut self, progress: usize);
     fn finish(&mut self);
+
+    /// Polled between the units of work, returning `true` aborts the operation with [`MangaiError::Cancelled`]
+    fn is_cancelled(&mut self) -> bool {
+        false
+    }
 }
 
 pub struct MangaiClean {
     model: model::Model,
+    model_info: model::ModelInfo,
 }
 
 impl MangaiClean {
     pub fn new_from_bytes<B: AsRef<[u8]>>(bytes: B) -> Result<Self> {
-        let model = model::Model::new_from_bytes(bytes)?;
-        Ok(Self { model })
+        let bytes = bytes.as_ref();
+        let model_info = model::ModelInfo::from_bytes(bytes).map_err(MangaiError::ModelLoad)?;
+        info!("Model info: {:?}", model_info);
+        let model = model::Model::new_from_bytes(bytes).map_err(MangaiError::ModelLoad)?;
+        Ok(Self { model, model_info })
     }
 
     pub fn new(progress: &mut dyn ProgressReporter) -> Result<Self> {
-        let bytes = model_registry::get_model(progress)?;
+        let bytes = model_registry::get_model(progress).map_err(|e| {
+            // cancellation is reported by the registry as a MangaiError, keep it as is
+            e.downcast::<MangaiError>()
+                .unwrap_or_else(MangaiError::ModelLoad)
+        })?;
         Self::new_from_bytes(bytes)
     }
 
-    pub fn clean_one_batch(&self, image_in: ArrayView3<u8>, mut mask_out: ArrayViewMut2<bool>) {
-        let mut image_buf = Array3::zeros(image_in.dim().into_shape());
-        // TODO: most of this code can be shared with the tract version
-        Zip::from(&mut image_buf).and(image_in).for_each(|a, b| {
-            let f = *b as f32 / 255.0;
-            // normalize
-            *a = (f - 0.5) / 0.5;
-        });
+    /// (height, width) of the tiles the model takes, see [`Self::run_batches`]
+    pub fn tile_size(&self) -> (usize, usize) {
+        self.model_info.tile_size
+    }
 
-        assert_eq!(image_in.shape(), &MODEL_INPUT_SHAPE[1..]);
-        assert_eq!(mask_out.shape(), &MODEL_INPUT_SHAPE[2..]);
+    /// Threshold of the model output, either given in the options or the one the model comes with
+    fn threshold(&self, options: &CleanOptions) -> f32 {
+        options.threshold.unwrap_or(self.model_info.threshold)
+    }
 
-        let image_buf = image_buf
-            .into_shape(MODEL_INPUT_SHAPE)
-            .unwrap()
-            .into_owned();
+    /// How many batches can be stacked into a single model invocation
+    fn group_size(&self, options: &CleanOptions) -> usize {
+        if self.model_info.dynamic_batch {
+            options.max_batch_size.max(1)
+        } else {
+            1
+        }
+    }
 
-        let model_output = self.model.run_model(image_buf);
+    /// Runs the model on several RGB tiles of [`Self::tile_size`] at once, returning the raw text probabilities for each
+    /// of them
+    ///
+    /// Passing more than one tile requires a model with a dynamic batch axis
+    pub fn run_batches<T: Sample>(&self, images_in: &[ArrayView3<T>]) -> Result<Vec<Array2<f32>>> {
+        let (tile_height, tile_width) = self.tile_size();
+        let normalization = self.model_info.normalization;
 
-        let model_output = model_output
-            .into_shape((1, BATCH_HEIGHT, BATCH_WIDTH))
-            .unwrap();
-        let mut mask = model_output.mapv(|x: f32| x > THRESHOLD);
+        let mut image_buf = Array4::zeros((images_in.len(), 3, tile_height, tile_width));
+        for (image_in, mut image_buf) in images_in.iter().zip(image_buf.outer_iter_mut()) {
+            MangaiError::check_shape("batch input", image_buf.shape(), image_in.shape())?;
+
+            // TODO: most of this code can be shared with the tract version
+            Zip::from(&mut image_buf).and(image_in).for_each(|a, b| {
+                *a = normalization.apply(b.to_unit());
+            });
+        }
+
+        let model_output = self
+            .model
+            .run_model(image_buf)
+            .map_err(MangaiError::Inference)?;
+        MangaiError::check_shape(
+            "model output",
+            &[images_in.len(), 1, tile_height, tile_width],
+            model_output.shape(),
+        )?;
+
+        Ok(model_output
+            .outer_iter()
+            .map(|output| output.index_axis(Axis(0), 0).to_owned())
+            .collect())
+    }
 
-        let kern = ndarray::arr2(&[[true, true, true], [true, true, true], [true, true, true]]);
+    /// Runs the model on a single RGB tile of [`Self::tile_size`], returning the raw text probabilities
+    pub fn run_one_batch<T: Sample>(&self, image_in: ArrayView3<T>) -> Result<Array2<f32>> {
+        let mut outputs = self.run_batches(&[image_in])?;
+        Ok(outputs.remove(0))
+    }
+
+    /// Computes the thresholded and dilated masks of batch-sized tiles
+    fn detect_batch_masks<T: Sample>(
+        &self,
+        images_in: &[ArrayView3<T>],
+        options: &CleanOptions,
+    ) -> Result<Vec<Array2<bool>>> {
+        let model_outputs = augment::run_augmented(self, images_in, options)?;
 
-        let mut dilating_mask = mask.view_mut();
-        dilating_mask.swap_axes(0, 2);
-        dilating_mask.dilate_inplace(kern.view());
-        dilating_mask.dilate_inplace(kern.view());
+        Ok(model_outputs
+            .into_iter()
+            .map(|model_output| self.threshold_probabilities(model_output.view(), options))
+            .collect())
+    }
 
-        let mask = mask.into_shape((BATCH_HEIGHT, BATCH_WIDTH)).unwrap();
+    /// ORs the mask of a single tile of [`Self::tile_size`] into `mask_out`
+    pub fn clean_one_batch<T: Sample>(
+        &self,
+        image_in: ArrayView3<T>,
+        mut mask_out: ArrayViewMut2<bool>,
+        options: &CleanOptions,
+    ) -> Result<()> {
+        let (tile_height, tile_width) = self.tile_size();
+        MangaiError::check_shape("batch mask", &[tile_height, tile_width], mask_out.shape())?;
 
-        // perform OR operation on intersecting areas
-        // it's not really clear how this affects the result, but let's try it
+        let mask = self.detect_batch_masks(&[image_in], options)?.remove(0);
 
+        // the neighbouring batches overlap, so keep whatever the others have already found there
         Zip::from(&mut mask_out).and(&mask).for_each(|a, &b| {
             if b {
                 *a = true;
             }
         });
+
+        Ok(())
+    }
+
+    fn dilate_mask(mask: &mut Array2<bool>, options: &CleanOptions) {
+        let kern = options.dilation_kernel();
+
+        let mut dilating_mask = mask.view_mut().insert_axis(Axis(2));
+        for _ in 0..options.dilation_iterations {
+            dilating_mask.dilate_inplace(kern.view());
+        }
     }
 
-    pub fn clean_page(
+    /// Thresholds, post-processes and dilates model output
+    fn threshold_probabilities(
         &self,
-        image_in: ArrayView3<u8>,
-        mut image_out: ArrayViewMut3<u8>,
-        progress_reporter: &mut dyn ProgressReporter,
+        probabilities: ArrayView2<f32>,
+        options: &CleanOptions,
+    ) -> Array2<bool> {
+        let threshold = self.threshold(options);
+        let mut mask = probabilities.mapv(|x| x > threshold);
+        if let Some(post_processing) = &options.post_processing {
+            postprocess::post_process(&mut mask, post_processing);
+        }
+        Self::dilate_mask(&mut mask, options);
+        mask
+    }
+
+    /// Applies the page-level refinements of the mask, which need to see the original (unpadded) image
+    fn refine_mask<T: Sample>(
+        image_in: ArrayView3<T>,
+        mask: &mut Array2<bool>,
+        options: &CleanOptions,
     ) {
-        assert_eq!(image_in.dim(), image_out.dim());
+        if let Some(bubble_options) = &options.bubbles {
+            let text = Self::undilated_mask(mask, options);
+            bubbles::expand_to_bubbles(image_in, mask, text.view(), bubble_options);
+        }
+    }
+
+    /// The page mask before the dilation, as far as it can be told from the dilated one
+    ///
+    /// Eroding by the dilation kernel gives the closing of the original mask, which still covers all of it. The dilation
+    /// was done at the model scale, so only as many steps as fit into the page scale are undone
+    fn undilated_mask(mask: &Array2<bool>, options: &CleanOptions) -> Array2<bool> {
+        let kernel = options.dilation_kernel();
+        let steps = (options.dilation_iterations as f32 / options.model_scale()).floor() as usize;
 
-        let (channels, orig_height, orig_width) = image_in.dim();
-        assert_eq!(channels, 3);
+        let mut text = mask.clone();
+        for _ in 0..steps {
+            text = postprocess::erode(text.view(), kernel.view());
+        }
+        text
+    }
 
-        // pad the image if it's too small
-        let (image_in, height, width) = if orig_height < BATCH_HEIGHT || orig_width < BATCH_WIDTH {
-            let height = BATCH_HEIGHT.max(orig_height);
-            let width = BATCH_WIDTH.max(orig_width);
+    fn detect_padded_text_mask<T: Sample>(
+        &self,
+        image_in: &impl TileSource<T>,
+        options: &CleanOptions,
+        progress_reporter: &mut dyn ProgressReporter,
+    ) -> Result<Array2<bool>> {
+        if options.blend_mode != BlendMode::Or {
+            let probabilities =
+                self.detect_padded_text_probabilities(image_in, options, progress_reporter)?;
 
-            info!(
-                "Padding the image to fit the batch size (padded size is {}x{})",
-                width, height
-            );
-            let mut padded_image = Array3::from_elem((3, height, width), 255u8);
-            padded_image
-                .slice_mut(s![.., ..orig_height, ..orig_width])
-                .assign(&image_in);
+            // the batches are already merged, so threshold & dilate the whole page at once
+            return Ok(self.threshold_probabilities(probabilities.view(), options));
+        }
+
+        let (_, height, width) = image_in.dim();
+        let mut mask = Array2::from_elem((height, width), false);
 
-            (CowArray::from(padded_image), height, width)
+        pipeline::run_batches(
+            image_in,
+            options.num_workers,
+            self.group_size(options),
+            self.tile_size(),
+            options.tile_margin,
+            progress_reporter,
+            |images_in| self.detect_batch_masks(images_in, options),
+            |batch_mask, (top, left)| {
+                let (height, width) = batch_mask.dim();
+                let mut mask_out = mask.slice_mut(s![top..top + height, left..left + width]);
+
+                // perform OR operation on intersecting areas
+                Zip::from(&mut mask_out).and(&batch_mask).for_each(|a, &b| {
+                    if b {
+                        *a = true;
+                    }
+                });
+                Ok(())
+            },
+        )?;
+
+        Ok(mask)
+    }
+
+    fn detect_padded_text_probabilities<T: Sample>(
+        &self,
+        image_in: &impl TileSource<T>,
+        options: &CleanOptions,
+        progress_reporter: &mut dyn ProgressReporter,
+    ) -> Result<Array2<f32>> {
+        let (_, height, width) = image_in.dim();
+        let mut accumulator = ProbabilityAccumulator::new(height, width, options.blend_mode);
+
+        pipeline::run_batches(
+            image_in,
+            options.num_workers,
+            self.group_size(options),
+            self.tile_size(),
+            options.tile_margin,
+            progress_reporter,
+            |images_in| augment::run_augmented(self, images_in, options),
+            |batch_probabilities, origin| {
+                accumulator.add(origin, batch_probabilities.view());
+                Ok(())
+            },
+        )?;
+
+        Ok(accumulator.finish())
+    }
+
+    /// Runs `detect` on the page, and then on the inverted page if [`CleanOptions::detect_inverted`] is set
+    fn with_inverted<T: Sample, R>(
+        image_in: ArrayView3<T>,
+        options: &CleanOptions,
+        mut detect: impl FnMut(ArrayView3<T>) -> Result<R>,
+    ) -> Result<(R, Option<R>)> {
+        let regular = detect(image_in)?;
+        let inverted = if options.detect_inverted {
+            let inverted_image = image_in.mapv(Sample::invert);
+            Some(detect(inverted_image.view())?)
         } else {
-            (CowArray::from(image_in), orig_height, orig_width)
+            None
         };
 
-        let mut mask = ndarray::Array2::from_shape_fn((height, width), |_| false);
+        Ok((regular, inverted))
+    }
 
-        let batcher = batcher::Batcher::new(height, width);
-        progress_reporter.init(ProgressKind::Items, "Cleaning manga", batcher.num_batches());
-        for (i, slice) in batcher.iter().enumerate() {
-            progress_reporter.progress(i);
-            info!("Processing batch #{}/{}", i + 1, batcher.num_batches());
+    /// Mask of the page, detected at the scale requested by [`CleanOptions::resolution`] and resized back
+    ///
+    /// Only the batches overlapping `include_mask` are run through the model
+    fn page_text_mask<T: Sample>(
+        &self,
+        image_in: ArrayView3<T>,
+        include_mask: Option<ArrayView2<bool>>,
+        options: &CleanOptions,
+        progress_reporter: &mut dyn ProgressReporter,
+    ) -> Result<Array2<bool>> {
+        let (_, height, width) = image_in.dim();
 
-            let image_in = image_in.slice(slice);
-            let mask_slice = [slice.deref()[1], slice.deref()[2]];
-            let mask_slice = SliceInfo::try_from(mask_slice).unwrap();
+        let scaled_image = resample::scale_page(image_in, options.model_scale())?;
+        let (_, scaled_height, scaled_width) = scaled_image.dim();
+        let padded_image = page::pad(scaled_image.view(), self.tile_size());
+        let tiles = IncludedTiles {
+            image: padded_image.view(),
+            unpadded_size: (scaled_height, scaled_width),
+            include_mask,
+        };
+        let mask = self.detect_padded_text_mask(&tiles, options, progress_reporter)?;
+
+        // slice the mask to undo the padding
+        let mask = mask.slice_move(s![..scaled_height, ..scaled_width]);
+        let mut mask = resample::resize_mask(mask, height, width);
+        Self::refine_mask(image_in, &mut mask, options);
+
+        Ok(mask)
+    }
+
+    /// Model output for the page, at the scale requested by [`CleanOptions::resolution`]
+    fn scaled_page_text_probabilities<T: Sample>(
+        &self,
+        image_in: ArrayView3<T>,
+        options: &CleanOptions,
+        progress_reporter: &mut dyn ProgressReporter,
+    ) -> Result<Array2<f32>> {
+        let scaled_image = resample::scale_page(image_in, options.model_scale())?;
+        let (_, scaled_height, scaled_width) = scaled_image.dim();
+        let padded_image = page::pad(scaled_image.view(), self.tile_size());
+        let probabilities = self.detect_padded_text_probabilities(
+            &padded_image.view(),
+            options,
+            progress_reporter,
+        )?;
 
-            let mask_out = mask.slice_mut(mask_slice);
+        Ok(probabilities.slice_move(s![..scaled_height, ..scaled_width]))
+    }
+
+    /// Masks of the page, restricted to `include_mask` and never touching `exclude_mask` if given
+    fn detect_text_masks<T: Sample>(
+        &self,
+        image_in: ArrayView3<T>,
+        include_mask: Option<ArrayView2<bool>>,
+        exclude_mask: Option<ArrayView2<bool>>,
+        options: &CleanOptions,
+        progress_reporter: &mut dyn ProgressReporter,
+    ) -> Result<TextMasks> {
+        let layout = Layout::of(image_in)?;
+        let colors = layout.visible_colors(image_in);
 
-            self.clean_one_batch(image_in, mask_out);
+        let (_, height, width) = image_in.dim();
+        if let Some(include_mask) = &include_mask {
+            MangaiError::check_shape("include mask", &[height, width], include_mask.shape())?;
+        }
+        if let Some(exclude_mask) = &exclude_mask {
+            MangaiError::check_shape("exclude mask", &[height, width], exclude_mask.shape())?;
         }
 
-        progress_reporter.finish();
+        let (regular, inverted) = Self::with_inverted(colors.view(), options, |image_in| {
+            self.page_text_mask(image_in, include_mask, options, progress_reporter)
+        })?;
 
-        // slice the image to undo the padding
-        let image_in = image_in.slice(s![.., ..orig_height, ..orig_width]);
-        let mask = mask.slice(s![..orig_height, ..orig_width]);
+        let mut masks = TextMasks { regular, inverted };
+        if let Some(opaque) = layout.opaque(image_in) {
+            masks.retain(opaque.view());
+        }
+        if let Some(include_mask) = include_mask {
+            masks.retain(include_mask);
+        }
+        if let Some(exclude_mask) = exclude_mask {
+            masks.retain((!&exclude_mask).view());
+        }
 
-        Zip::from(&mut image_out)
-            .and(mask.broadcast(image_in.dim()).unwrap())
-            .and(&image_in)
-            .for_each(|out, &mask, &value| {
-                if mask {
-                    *out = 255;
-                } else {
-                    *out = value;
-                }
-            });
+        Ok(masks)
+    }
+
+    /// Computes the mask of pixels that would be cleaned by [`Self::clean_page`], without modifying the image
+    ///
+    /// The page is in (channels, height, width) layout, either grayscale or RGB, optionally followed by an alpha
+    /// channel. Fully transparent pixels are treated as blank paper, and are never in the mask
+    pub fn detect_text_mask<T: Sample>(
+        &self,
+        image_in: ArrayView3<T>,
+        options: &CleanOptions,
+        progress_reporter: &mut dyn ProgressReporter,
+    ) -> Result<Array2<bool>> {
+        Ok(self
+            .detect_text_masks(image_in, None, None, options, progress_reporter)?
+            .merged())
+    }
+
+    /// Returns the model output for the whole page (before thresholding and dilation)
+    ///
+    /// Outputs of the overlapping batches are merged according to [`CleanOptions::blend_mode`]. With
+    /// [`CleanOptions::detect_inverted`], this is the maximum of the outputs for the page and the inverted page
+    pub fn detect_text_probabilities<T: Sample>(
+        &self,
+        image_in: ArrayView3<T>,
+        options: &CleanOptions,
+        progress_reporter: &mut dyn ProgressReporter,
+    ) -> Result<Array2<f32>> {
+        let layout = Layout::of(image_in)?;
+        let colors = layout.visible_colors(image_in);
+
+        let (_, height, width) = image_in.dim();
+        let probabilities = Self::with_inverted(colors.view(), options, |image_in| {
+            let probabilities =
+                self.scaled_page_text_probabilities(image_in, options, progress_reporter)?;
+            Ok(resample::resize_probabilities(probabilities, height, width))
+        })?;
+
+        Ok(merge_probabilities(probabilities))
+    }
+
+    /// Splits the text mask into connected regions, see [`TextRegion`]
+    ///
+    /// The mask is always computed from the merged model output, so with [`BlendMode::Or`] it can slightly differ from
+    /// [`Self::detect_text_mask`] near the batch edges
+    pub fn detect_text_regions<T: Sample>(
+        &self,
+        image_in: ArrayView3<T>,
+        options: &CleanOptions,
+        progress_reporter: &mut dyn ProgressReporter,
+    ) -> Result<Vec<TextRegion>> {
+        let layout = Layout::of(image_in)?;
+        let colors = layout.visible_colors(image_in);
+        let opaque = layout.opaque(image_in);
+
+        let (_, height, width) = image_in.dim();
+        let (regular, inverted) = Self::with_inverted(colors.view(), options, |image_in| {
+            let probabilities =
+                self.scaled_page_text_probabilities(image_in, options, progress_reporter)?;
+            // threshold & dilate at the model scale, like the other detection methods do
+            let mask = self.threshold_probabilities(probabilities.view(), options);
+
+            let probabilities = resample::resize_probabilities(probabilities, height, width);
+            let mut mask = resample::resize_mask(mask, height, width);
+            Self::refine_mask(image_in, &mut mask, options);
+            if let Some(opaque) = &opaque {
+                mask &= opaque;
+            }
+            Ok((mask, probabilities))
+        })?;
+
+        Ok(merge_regions(regular, inverted))
+    }
+
+    /// Cleans the page, writing the result to `image_out` of the same shape
+    ///
+    /// See [`Self::detect_text_mask`] for the supported layouts. The alpha channel is copied to the output as is
+    ///
+    /// `include_mask` restricts the cleaning to a part of the page (like a single panel), the batches entirely outside
+    /// of it are not even run through the model. The pixels in `exclude_mask` (like a logo or a signature) are copied to
+    /// the output unchanged. Both masks are (height, width) of the page, `None` includes the whole page and excludes
+    /// nothing
+    pub fn clean_page<T: Sample>(
+        &self,
+        image_in: ArrayView3<T>,
+        mut image_out: ArrayViewMut3<T>,
+        include_mask: Option<ArrayView2<bool>>,
+        exclude_mask: Option<ArrayView2<bool>>,
+        options: &CleanOptions,
+        progress_reporter: &mut dyn ProgressReporter,
+    ) -> Result<()> {
+        MangaiError::check_shape("output page", image_in.shape(), image_out.shape())?;
+        options.fill_mode.check()?;
+
+        let layout = Layout::of(image_in)?;
+        let masks = self.detect_text_masks(
+            image_in,
+            include_mask,
+            exclude_mask,
+            options,
+            progress_reporter,
+        )?;
+
+        masks.fill(
+            layout.colors(image_in),
+            layout.colors_mut(image_out.view_mut()),
+            options,
+        );
+        if let Some(alpha) = layout.alpha(image_in) {
+            image_out
+                .index_axis_mut(Axis(0), layout.color_channels())
+                .assign(&alpha);
+        }
+
+        Ok(())
+    }
+
+    /// Cleans the page band by band, reading it from `page_in` and writing the result to `page_out` top to bottom
+    ///
+    /// Unlike [`Self::clean_page`], never holds the whole page, its mask or the model output in memory, only a few
+    /// batch rows around the ones being processed, so it suits very tall pages like webtoon strips. The output rows are
+    /// written as soon as no later batch can affect them.
+    ///
+    /// The result is the same as with [`Self::clean_page`], except that the fill modes other than [`FillMode::Solid`]
+    /// see a limited number of rows around each band, so text taller than that can get a slightly different fill.
+    /// [`CleanOptions::bubbles`], [`CleanOptions::detect_inverted`] and [`CleanOptions::resolution`] need the whole
+    /// page, and fail with [`MangaiError::UnsupportedOption`]
+    ///
+    /// Unlike with [`Self::clean_page`], an error or a cancellation can leave `page_out` partially written: the bands
+    /// finished before it are already cleaned, the rest is untouched
+    pub fn clean_page_streaming<T: Sample>(
+        &self,
+        page_in: &impl PageSource<T>,
+        page_out: &mut impl PageSink<T>,
+        options: &CleanOptions,
+        progress_reporter: &mut dyn ProgressReporter,
+    ) -> Result<()> {
+        streaming::clean_page(self, page_in, page_out, options, progress_reporter)
+    }
+
+    /// Cleans a sequence of vertical strips (like the slices of a webtoon chapter) as a single continuous page
+    ///
+    /// The strips go top to bottom, and must have the same layout and width. Text crossing the boundaries between them
+    /// is detected and filled as a whole, and the result is written to `strips_out` of the same shapes. The page is
+    /// processed with [`Self::clean_page_streaming`], so the same limitations apply. In particular, an error or a
+    /// cancellation can leave the top of the page (up to the middle of some strip) already cleaned, and the rest
+    /// untouched
+    pub fn clean_strips<T: Sample>(
+        &self,
+        strips_in: &[ArrayView3<T>],
+        strips_out: &mut [ArrayViewMut3<T>],
+        options: &CleanOptions,
+        progress_reporter: &mut dyn ProgressReporter,
+    ) -> Result<()> {
+        MangaiError::check_shape("output strips", &[strips_in.len()], &[strips_out.len()])?;
+        for (strip_in, strip_out) in strips_in.iter().zip(strips_out.iter()) {
+            MangaiError::check_shape("output strip", strip_in.shape(), strip_out.shape())?;
+        }
+        if strips_in.is_empty() {
+            return Ok(());
+        }
+
+        let page_in = strips::Strips::new(strips_in)?;
+        let mut page_out = strips::StripsMut::new(strips_out)?;
+        self.clean_page_streaming(&page_in, &mut page_out, options, progress_reporter)
+    }
+
+    /// Grayscale version of [`Self::detect_text_mask`]
+    pub fn detect_grayscale_text_mask<T: Sample>(
+        &self,
+        image_in: ArrayView2<T>,
+        options: &CleanOptions,
+        progress_reporter: &mut dyn ProgressReporter,
+    ) -> Result<Array2<bool>> {
+        self.detect_text_mask(image_in.insert_axis(Axis(0)), options, progress_reporter)
+    }
+
+    /// Grayscale version of [`Self::detect_text_probabilities`]
+    pub fn detect_grayscale_text_probabilities<T: Sample>(
+        &self,
+        image_in: ArrayView2<T>,
+        options: &CleanOptions,
+        progress_reporter: &mut dyn ProgressReporter,
+    ) -> Result<Array2<f32>> {
+        self.detect_text_probabilities(image_in.insert_axis(Axis(0)), options, progress_reporter)
     }
 
-    pub fn clean_grayscale_page(
+    /// Grayscale version of [`Self::detect_text_regions`]
+    pub fn detect_grayscale_text_regions<T: Sample>(
         &self,
-        image_in: ArrayView2<u8>,
-        mut image_out: ArrayViewMut2<u8>,
+        image_in: ArrayView2<T>,
+        options: &CleanOptions,
         progress_reporter: &mut dyn ProgressReporter,
+    ) -> Result<Vec<TextRegion>> {
+        self.detect_text_regions(image_in.insert_axis(Axis(0)), options, progress_reporter)
+    }
+
+    /// Grayscale version of [`Self::clean_page`]
+    pub fn clean_grayscale_page<T: Sample>(
+        &self,
+        image_in: ArrayView2<T>,
+        image_out: ArrayViewMut2<T>,
+        include_mask: Option<ArrayView2<bool>>,
+        exclude_mask: Option<ArrayView2<bool>>,
+        options: &CleanOptions,
+        progress_reporter: &mut dyn ProgressReporter,
+    ) -> Result<()> {
+        self.clean_page(
+            image_in.insert_axis(Axis(0)),
+            image_out.insert_axis(Axis(0)),
+            include_mask,
+            exclude_mask,
+            options,
+            progress_reporter,
+        )
+    }
+}
+
+/// Mask of the dark text on light background, and of the light text on dark background if it was looked for
+struct TextMasks {
+    regular: Array2<bool>,
+    inverted: Option<Array2<bool>>,
+}
+
+impl TextMasks {
+    /// Drops the pixels outside of `keep` from the masks
+    fn retain(&mut self, keep: ArrayView2<bool>) {
+        self.regular &= &keep;
+        if let Some(inverted) = &mut self.inverted {
+            *inverted &= &keep;
+        }
+    }
+
+    fn merged(self) -> Array2<bool> {
+        match self.inverted {
+            Some(inverted) => self.regular | inverted,
+            None => self.regular,
+        }
+    }
+
+    fn fill<T: Sample>(
+        &self,
+        image_in: ArrayView3<T>,
+        mut image_out: ArrayViewMut3<T>,
+        options: &CleanOptions,
     ) {
-        assert_eq!(image_in.dim(), image_out.dim());
+        match &self.inverted {
+            None => fill::fill_page(image_in, self.regular.view(), image_out, options),
+            Some(inverted) => {
+                // the light text must get the dark fill, even if the regular pass has found it too
+                let regular = Zip::from(&self.regular)
+                    .and(inverted)
+                    .map_collect(|&regular, &inverted| regular && !inverted);
+
+                fill::fill_page(image_in, regular.view(), image_out.view_mut(), options);
+                fill::fill_inverted(image_in, inverted.view(), image_out);
+            }
+        }
+    }
+}
 
-        let (orig_height, orig_width) = image_in.dim();
+fn merge_probabilities((regular, inverted): (Array2<f32>, Option<Array2<f32>>)) -> Array2<f32> {
+    match inverted {
+        Some(inverted) => Zip::from(&regular)
+            .and(&inverted)
+            .map_collect(|&a, &b| a.max(b)),
+        None => regular,
+    }
+}
+
+fn merge_regions(
+    (regular_mask, regular_probabilities): (Array2<bool>, Array2<f32>),
+    inverted: Option<(Array2<bool>, Array2<f32>)>,
+) -> Vec<TextRegion> {
+    let (mask, probabilities) = match inverted {
+        Some((inverted_mask, inverted_probabilities)) => (
+            regular_mask | inverted_mask,
+            merge_probabilities((regular_probabilities, Some(inverted_probabilities))),
+        ),
+        None => (regular_mask, regular_probabilities),
+    };
+
+    regions::extract_regions(mask.view(), probabilities.view())
+}
+
+#[cfg(test)]
+impl MangaiClean {
+    /// The stub model with `tile_size` tiles, so that small pages take many batches
+    pub(crate) fn stub(tile_size: (usize, usize)) -> Self {
+        let mut clean = Self::new_from_bytes([]).unwrap();
+        clean.model_info.tile_size = tile_size;
+        clean.model_info.dynamic_batch = true;
+        clean
+    }
+}
+
+/// Progress reporter for the tests, which never cancels
+#[cfg(test)]
+pub(crate) struct NoProgress;
+
+#[cfg(test)]
+impl ProgressReporter for NoProgress {
+    fn init(&mut self, _kind: ProgressKind, _operation: &str, _total: usize) {}
+
+    fn progress(&mut self, _progress: usize) {}
+
+    fn finish(&mut self) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ndarray::Array3;
+
+    /// Cancels once it has been polled `polls` times
+    struct CancelAfter {
+        polls: usize,
+    }
+
+    impl ProgressReporter for CancelAfter {
+        fn init(&mut self, _kind: ProgressKind, _operation: &str, _total: usize) {}
+
+        fn progress(&mut self, _progress: usize) {}
+
+        fn finish(&mut self) {}
+
+        fn is_cancelled(&mut self) -> bool {
+            if self.polls == 0 {
+                return true;
+            }
+            self.polls -= 1;
+            false
+        }
+    }
+
+    #[test]
+    fn test_cancel_leaves_output() {
+        let clean = MangaiClean::new_from_bytes([]).unwrap();
+        let (tile_height, tile_width) = clean.tile_size();
+
+        // a few batches, with some text in each
+        let mut page = Array3::from_elem((1, tile_height * 2, tile_width * 2), 230u8);
+        for y in (0..tile_height * 2).step_by(200) {
+            page.slice_mut(s![.., y..y + 20, 100..300]).fill(0);
+        }
 
-        // pad the image if it's too small
-        let (image_in, height, width) = if orig_height < BATCH_HEIGHT || orig_width < BATCH_WIDTH {
-            info!(
-                "Padding the image to fit the batch size ({}x{})",
-                BATCH_WIDTH, BATCH_HEIGHT
+        for polls in 0..3 {
+            let mut page_out = Array3::from_elem(page.dim(), 7u8);
+            let result = clean.clean_page(
+                page.view(),
+                page_out.view_mut(),
+                None,
+                None,
+                &CleanOptions::default(),
+                &mut CancelAfter { polls },
             );
-            let mut padded_image = Array2::from_elem((BATCH_HEIGHT, BATCH_WIDTH), 255u8);
-            padded_image
-                .slice_mut(s![..orig_height, ..orig_width])
-                .assign(&image_in);
 
-            (CowArray::from(padded_image), BATCH_HEIGHT, BATCH_WIDTH)
-        } else {
-            (CowArray::from(image_in), orig_height, orig_width)
+            assert!(matches!(result, Err(MangaiError::Cancelled)), "{}", polls);
+            assert!(page_out.iter().all(|&value| value == 7), "{}", polls);
+        }
+    }
+
+    #[test]
+    fn test_empty_page() {
+        let clean = MangaiClean::new_from_bytes([]).unwrap();
+        let scaled = CleanOptions {
+            resolution: Some(Resolution::Dpi {
+                page: 300.0,
+                target: 600.0,
+            }),
+            ..Default::default()
         };
 
-        let mut mask = ndarray::Array2::from_shape_fn((height, width), |_| false);
+        for options in [CleanOptions::default(), scaled] {
+            for size in [(0, 100), (100, 0)] {
+                let page = Array2::<u8>::zeros(size);
+                let mut page_out = Array2::zeros(size);
+                clean
+                    .clean_grayscale_page(
+                        page.view(),
+                        page_out.view_mut(),
+                        None,
+                        None,
+                        &options,
+                        &mut NoProgress,
+                    )
+                    .unwrap();
+            }
+        }
+    }
 
-        let batcher = batcher::Batcher::new(height, width);
-        progress_reporter.init(ProgressKind::Items, "Cleaning Manga", batcher.num_batches());
-        for (i, slice) in batcher.iter().enumerate() {
-            progress_reporter.progress(i);
-            info!("Processing batch #{}/{}", i + 1, batcher.num_batches());
+    #[test]
+    fn test_empty_strips() {
+        let clean = MangaiClean::new_from_bytes([]).unwrap();
+        let strip = Array3::<u8>::zeros((1, 0, 100));
+        let mut strip_out = Array3::zeros((1, 0, 100));
+        clean
+            .clean_strips(
+                &[strip.view()],
+                &mut [strip_out.view_mut()],
+                &CleanOptions::default(),
+                &mut NoProgress,
+            )
+            .unwrap();
+    }
 
-            let image_in = image_in.broadcast((3, height, width)).unwrap();
-            let image_in = image_in.slice(slice);
+    #[test]
+    fn test_strips_same_as_page() {
+        let clean = MangaiClean::stub((64, 48));
+        let mut page = Array3::from_elem((1, 200, 100), 230u8);
+        // text crossing the boundaries at 70 and 80
+        page.slice_mut(s![.., 64..86, 20..60]).fill(20);
+        page.slice_mut(s![.., 150..160, 10..30]).fill(20);
+        let bounds = [0, 70, 80, 133, 200];
+        let options = CleanOptions::default();
 
-            let mask_slice = [slice.deref()[1], slice.deref()[2]];
-            let mask_slice = SliceInfo::try_from(mask_slice).unwrap();
+        let mut expected = Array3::zeros(page.dim());
+        clean
+            .clean_page(
+                page.view(),
+                expected.view_mut(),
+                None,
+                None,
+                &options,
+                &mut NoProgress,
+            )
+            .unwrap();
 
-            let mask_out = mask.slice_mut(mask_slice);
+        let strips = bounds
+            .windows(2)
+            .map(|rows| page.slice(s![.., rows[0]..rows[1], ..]))
+            .collect::<Vec<_>>();
+        let mut strips_out = strips
+            .iter()
+            .map(|strip| Array3::zeros(strip.dim()))
+            .collect::<Vec<_>>();
+        clean
+            .clean_strips(
+                &strips,
+                &mut strips_out
+                    .iter_mut()
+                    .map(|strip| strip.view_mut())
+                    .collect::<Vec<_>>(),
+                &options,
+                &mut NoProgress,
+            )
+            .unwrap();
 
-            self.clean_one_batch(image_in, mask_out);
+        for (rows, strip_out) in bounds.windows(2).zip(&strips_out) {
+            assert_eq!(strip_out, expected.slice(s![.., rows[0]..rows[1], ..]));
         }
+        // the 10 row strip is all text
+        assert!(strips_out[1]
+            .slice(s![.., .., 20..60])
+            .iter()
+            .all(|&value| value == 255));
+    }
 
-        progress_reporter.progress(batcher.num_batches());
+    /// Remembers the number of batches
+    struct CountBatches(usize);
 
-        // slice the image to undo the padding
-        let image_in = image_in.slice(s![..orig_height, ..orig_width]);
-        let mask = mask.slice(s![..orig_height, ..orig_width]);
+    impl ProgressReporter for CountBatches {
+        fn init(&mut self, _kind: ProgressKind, _operation: &str, total: usize) {
+            self.0 = total;
+        }
 
-        Zip::from(&mut image_out)
-            .and(mask)
-            .and(&image_in)
-            .for_each(|out, &mask, &value| {
-                if mask {
-                    *out = 255;
-                } else {
-                    *out = value;
-                }
-            });
+        fn progress(&mut self, _progress: usize) {}
+
+        fn finish(&mut self) {}
+    }
+
+    #[test]
+    fn test_include_exclude_masks() {
+        let clean = MangaiClean::stub((64, 48));
+        let mut page = Array3::from_elem((1, 200, 150), 230u8);
+        for (i, y) in (5..190).step_by(15).enumerate() {
+            let x = (i * 37) % 120;
+            page.slice_mut(s![.., y..y + 6, x..x + 25]).fill(20);
+        }
+        let mut exclude = Array2::from_elem((200, 150), false);
+        exclude.slice_mut(s![40..80, 30..90]).fill(true);
+        let mut include = Array2::from_elem((200, 150), false);
+        include.slice_mut(s![..50, 20..]).fill(true);
+
+        let clean_page = |include: Option<&Array2<bool>>,
+                          exclude: Option<&Array2<bool>>,
+                          options: &CleanOptions| {
+            let mut page_out = Array3::zeros(page.dim());
+            let mut batches = CountBatches(0);
+            clean
+                .clean_page(
+                    page.view(),
+                    page_out.view_mut(),
+                    include.map(|mask| mask.view()),
+                    exclude.map(|mask| mask.view()),
+                    options,
+                    &mut batches,
+                )
+                .unwrap();
+            (page_out, batches.0)
+        };
+
+        let fill_modes = [
+            FillMode::Solid,
+            FillMode::Telea { radius: 3 },
+            FillMode::PatchMatch {
+                patch_radius: 2,
+                search_radius: 10,
+            },
+        ];
+        let resolutions = [
+            None,
+            Some(Resolution::Dpi {
+                page: 300.0,
+                target: 150.0,
+            }),
+        ];
+        for fill_mode in fill_modes {
+            for resolution in resolutions {
+                let options = CleanOptions {
+                    fill_mode,
+                    resolution,
+                    ..Default::default()
+                };
+                let (all_out, all_batches) = clean_page(None, None, &options);
+                assert_ne!(all_out, page);
+
+                let (page_out, _) = clean_page(None, Some(&exclude), &options);
+                Zip::from(&exclude)
+                    .and(page_out.index_axis(Axis(0), 0))
+                    .and(page.index_axis(Axis(0), 0))
+                    .for_each(|&excluded, &out, &value| {
+                        assert!(!excluded || out == value, "{:?}", options)
+                    });
+                // the text outside of the excluded region is still cleaned
+                assert_eq!(page_out[[0, 7, 10]], all_out[[0, 7, 10]]);
+                assert_ne!(page_out[[0, 7, 10]], page[[0, 7, 10]]);
+
+                let (page_out, batches) = clean_page(Some(&include), None, &options);
+                Zip::from(&include)
+                    .and(page_out.index_axis(Axis(0), 0))
+                    .and(page.index_axis(Axis(0), 0))
+                    .for_each(|&included, &out, &value| {
+                        assert!(included || out == value, "{:?}", options)
+                    });
+                assert_ne!(page_out[[0, 22, 60]], page[[0, 22, 60]]);
+                assert!(batches < all_batches, "{:?}", options);
+            }
+        }
+
+        let wrong_shape = Array2::from_elem((150, 200), false);
+        for (include, exclude) in [
+            (Some(wrong_shape.view()), None),
+            (None, Some(wrong_shape.view())),
+        ] {
+            let mut page_out = Array3::zeros(page.dim());
+            let result = clean.clean_page(
+                page.view(),
+                page_out.view_mut(),
+                include,
+                exclude,
+                &CleanOptions::default(),
+                &mut NoProgress,
+            );
+            assert!(matches!(result, Err(MangaiError::ShapeMismatch { .. })));
+        }
+    }
+
+    #[test]
+    fn test_alpha_pages() {
+        let clean = MangaiClean::stub((64, 48));
+
+        for channels in [2, 4] {
+            let color_channels = channels - 1;
+            let mut page = Array3::from_elem((channels, 100, 80), 230u8);
+            let mut alpha = Array2::from_shape_fn((100, 80), |(y, x)| (1 + (y + x) % 255) as u8);
+            // opaque text, and dark pixels nobody sees
+            page.slice_mut(s![..color_channels, 20..30, 20..60])
+                .fill(20);
+            alpha.slice_mut(s![20..30, 20..60]).fill(255);
+            page.slice_mut(s![..color_channels, 60..70, 20..60])
+                .fill(20);
+            alpha.slice_mut(s![60..70, 20..60]).fill(0);
+            page.index_axis_mut(Axis(0), color_channels).assign(&alpha);
+
+            let options = CleanOptions::default();
+            let mask = clean
+                .detect_text_mask(page.view(), &options, &mut NoProgress)
+                .unwrap();
+            assert!(mask[[25, 40]]);
+            assert!(!mask.slice(s![60..70, 20..60]).iter().any(|&masked| masked));
+
+            let mut page_out = Array3::zeros(page.dim());
+            clean
+                .clean_page(
+                    page.view(),
+                    page_out.view_mut(),
+                    None,
+                    None,
+                    &options,
+                    &mut NoProgress,
+                )
+                .unwrap();
+            assert_eq!(page_out.index_axis(Axis(0), color_channels), alpha);
+            assert!(page_out
+                .slice(s![..color_channels, 20..30, 20..60])
+                .iter()
+                .all(|&value| value == 255));
+            assert_eq!(
+                page_out.slice(s![.., 60..70, ..]),
+                page.slice(s![.., 60..70, ..]),
+                "{}",
+                channels
+            );
+        }
+    }
+
+    #[test]
+    fn test_telea_radius_0() {
+        let clean = MangaiClean::stub((64, 48));
+        let page = Array3::from_elem((1, 100, 80), 230u8);
+        let mut page_out = Array3::zeros(page.dim());
+        let options = CleanOptions {
+            fill_mode: FillMode::Telea { radius: 0 },
+            ..Default::default()
+        };
+
+        let result = clean.clean_page(
+            page.view(),
+            page_out.view_mut(),
+            None,
+            None,
+            &options,
+            &mut NoProgress,
+        );
+        assert!(matches!(result, Err(MangaiError::UnsupportedOption { .. })));
+        let result = clean.clean_page_streaming(
+            &page.view(),
+            &mut page_out.view_mut(),
+            &options,
+            &mut NoProgress,
+        );
+        assert!(matches!(result, Err(MangaiError::UnsupportedOption { .. })));
+    }
+
+    /// Gray paper with a dark box, and a light spot inside of it
+    ///
+    /// The gray is exactly in the middle, so the stub model finds neither the paper nor the inverted paper to be text
+    fn inverted_page() -> Array3<f32> {
+        let mut page = Array3::from_elem((1, 300, 400), 0.5);
+        page.slice_mut(s![.., 100..140, 100..200]).fill(0.1);
+        page.slice_mut(s![.., 118..122, 148..152]).fill(0.9);
+        page
+    }
+
+    #[test]
+    fn test_inverted_overrides_regular() {
+        let clean = MangaiClean::new_from_bytes([]).unwrap();
+        let page = inverted_page();
+        let options = CleanOptions {
+            detect_inverted: true,
+            ..Default::default()
+        };
+
+        let masks = clean
+            .detect_text_masks(page.view(), None, None, &options, &mut NoProgress)
+            .unwrap();
+        let inverted = masks.inverted.as_ref().unwrap();
+        // the dilated box covers the spot too
+        assert!(masks.regular[[120, 150]] && inverted[[120, 150]]);
+        assert!(!inverted[[105, 110]] && !inverted[[10, 10]]);
+
+        let mut page_out = Array3::zeros(page.dim());
+        clean
+            .clean_page(
+                page.view(),
+                page_out.view_mut(),
+                None,
+                None,
+                &options,
+                &mut NoProgress,
+            )
+            .unwrap();
+        // the spot gets the color of the box around it, not the white of the regular fill
+        assert_eq!(page_out[[0, 120, 150]], 0.1);
+        assert_eq!(page_out[[0, 105, 110]], 1.0);
+        assert_eq!(page_out[[0, 10, 10]], 0.5);
+    }
+
+    #[test]
+    fn test_inverted_disabled() {
+        let clean = MangaiClean::new_from_bytes([]).unwrap();
+        let page = inverted_page();
+        let options = CleanOptions::default();
+        assert!(!options.detect_inverted);
+
+        let masks = clean
+            .detect_text_masks(page.view(), None, None, &options, &mut NoProgress)
+            .unwrap();
+        assert!(masks.inverted.is_none());
+
+        let mut page_out = Array3::zeros(page.dim());
+        clean
+            .clean_page(
+                page.view(),
+                page_out.view_mut(),
+                None,
+                None,
+                &options,
+                &mut NoProgress,
+            )
+            .unwrap();
+        assert_eq!(page_out[[0, 120, 150]], 1.0);
+        assert_eq!(page_out[[0, 105, 110]], 1.0);
     }
 }
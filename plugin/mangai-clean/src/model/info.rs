@@ -0,0 +1,267 @@
+//! Just enough of the ONNX protobuf schema to inspect the model inputs without going through the inference backend
+
+use super::{BATCH_HEIGHT, BATCH_WIDTH, THRESHOLD};
+use anyhow::{Context, Result};
+use prost::Message;
+use std::str::FromStr;
+
+#[derive(Clone, PartialEq, Message)]
+struct ModelProto {
+    #[prost(message, optional, tag = "7")]
+    graph: Option<GraphProto>,
+    #[prost(message, repeated, tag = "14")]
+    metadata_props: Vec<StringStringEntryProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct StringStringEntryProto {
+    #[prost(string, tag = "1")]
+    key: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct GraphProto {
+    #[prost(message, repeated, tag = "11")]
+    input: Vec<ValueInfoProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ValueInfoProto {
+    #[prost(message, optional, tag = "2")]
+    r#type: Option<TypeProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TypeProto {
+    #[prost(message, optional, tag = "1")]
+    tensor_type: Option<TensorTypeProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TensorTypeProto {
+    #[prost(message, optional, tag = "2")]
+    shape: Option<TensorShapeProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TensorShapeProto {
+    #[prost(message, repeated, tag = "1")]
+    dim: Vec<DimensionProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct DimensionProto {
+    #[prost(int64, optional, tag = "1")]
+    dim_value: Option<i64>,
+    #[prost(string, optional, tag = "2")]
+    dim_param: Option<String>,
+}
+
+/// Model input is `(x - mean) / std`, where `x` goes from `0.0` (black) to `1.0` (white)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Normalization {
+    pub mean: f32,
+    pub std: f32,
+}
+
+impl Normalization {
+    pub fn apply(&self, x: f32) -> f32 {
+        (x - self.mean) / self.std
+    }
+}
+
+/// Properties of the model that affect how we feed it
+///
+/// Read from the input shape and the `metadata_props` of the model (`tile_height`, `tile_width`, `normalization_mean`,
+/// `normalization_std` and `threshold`), falling back to the values of the original model
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelInfo {
+    /// Whether the model accepts more than one image per inference
+    pub dynamic_batch: bool,
+    /// (height, width) of the images the model takes
+    pub tile_size: (usize, usize),
+    pub normalization: Normalization,
+    /// Model output values above this are considered text, unless overridden by [`crate::CleanOptions::threshold`]
+    pub threshold: f32,
+}
+
+/// Parses the metadata value under `key`, if there is one
+fn metadata<T: FromStr>(props: &[StringStringEntryProto], key: &str) -> Result<Option<T>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    props
+        .iter()
+        .find(|prop| prop.key == key)
+        .map(|prop| {
+            prop.value
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid model metadata {} = {:?}", key, prop.value))
+        })
+        .transpose()
+}
+
+/// Size of a spatial axis of the input, if it's fixed
+fn fixed_dim(dim: Option<&DimensionProto>) -> Option<usize> {
+    match dim {
+        Some(DimensionProto {
+            dim_value: Some(value),
+            ..
+        }) if *value > 0 => Some(*value as usize),
+        _ => None,
+    }
+}
+
+impl ModelInfo {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let model = ModelProto::decode(bytes)?;
+        let props = &model.metadata_props;
+
+        let input_shape = model
+            .graph
+            .and_then(|graph| graph.input.into_iter().next())
+            .and_then(|input| input.r#type)
+            .and_then(|ty| ty.tensor_type)
+            .and_then(|tensor| tensor.shape);
+
+        // the batch axis is dynamic if it's either named (like "N") or not specified at all
+        let dynamic_batch = match input_shape.as_ref().and_then(|shape| shape.dim.first()) {
+            Some(DimensionProto {
+                dim_value: Some(value),
+                ..
+            }) => *value <= 0,
+            Some(_) => true,
+            None => false,
+        };
+
+        // the declared shape is what the model will actually accept, so it takes precedence over the metadata
+        let dims = input_shape.as_ref().map_or(&[][..], |shape| &shape.dim[..]);
+        let tile_height = fixed_dim(dims.get(2))
+            .or(metadata(props, "tile_height")?)
+            .unwrap_or(BATCH_HEIGHT);
+        let tile_width = fixed_dim(dims.get(3))
+            .or(metadata(props, "tile_width")?)
+            .unwrap_or(BATCH_WIDTH);
+        anyhow::ensure!(
+            tile_height > 0 && tile_width > 0,
+            "invalid model tile size {}x{}",
+            tile_width,
+            tile_height
+        );
+
+        let normalization = Normalization {
+            mean: metadata(props, "normalization_mean")?.unwrap_or(0.5),
+            std: metadata(props, "normalization_std")?.unwrap_or(0.5),
+        };
+        anyhow::ensure!(
+            normalization.std != 0.0,
+            "invalid model normalization {:?}",
+            normalization
+        );
+
+        Ok(Self {
+            dynamic_batch,
+            tile_size: (tile_height, tile_width),
+            normalization,
+            threshold: metadata(props, "threshold")?.unwrap_or(THRESHOLD),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn model(dim: Vec<DimensionProto>, metadata: &[(&str, &str)]) -> Vec<u8> {
+        ModelProto {
+            graph: Some(GraphProto {
+                input: vec![ValueInfoProto {
+                    r#type: Some(TypeProto {
+                        tensor_type: Some(TensorTypeProto {
+                            shape: Some(TensorShapeProto { dim }),
+                        }),
+                    }),
+                }],
+            }),
+            metadata_props: metadata
+                .iter()
+                .map(|&(key, value)| StringStringEntryProto {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+        }
+        .encode_to_vec()
+    }
+
+    fn model_with_batch_dim(dim: DimensionProto) -> Vec<u8> {
+        model(vec![dim], &[])
+    }
+
+    fn fixed(value: i64) -> DimensionProto {
+        DimensionProto {
+            dim_value: Some(value),
+            dim_param: None,
+        }
+    }
+
+    #[test]
+    fn test_fixed_batch() {
+        let bytes = model_with_batch_dim(DimensionProto {
+            dim_value: Some(1),
+            dim_param: None,
+        });
+        assert!(!ModelInfo::from_bytes(&bytes).unwrap().dynamic_batch);
+    }
+
+    #[test]
+    fn test_dynamic_batch() {
+        let bytes = model_with_batch_dim(DimensionProto {
+            dim_value: None,
+            dim_param: Some("N".to_string()),
+        });
+        assert!(ModelInfo::from_bytes(&bytes).unwrap().dynamic_batch);
+    }
+
+    #[test]
+    fn test_defaults() {
+        let info = ModelInfo::from_bytes(&model_with_batch_dim(fixed(1))).unwrap();
+        assert_eq!(info.tile_size, (BATCH_HEIGHT, BATCH_WIDTH));
+        assert_eq!(info.normalization.apply(1.0), 1.0);
+        assert_eq!(info.threshold, THRESHOLD);
+    }
+
+    #[test]
+    fn test_metadata() {
+        let dynamic = DimensionProto {
+            dim_value: None,
+            dim_param: Some("H".to_string()),
+        };
+        let metadata = [
+            ("tile_height", "512"),
+            ("tile_width", "256"),
+            ("normalization_mean", "0"),
+            ("normalization_std", "1"),
+            ("threshold", "0.5"),
+        ];
+
+        let info =
+            ModelInfo::from_bytes(&model(vec![fixed(1), fixed(3), dynamic], &metadata)).unwrap();
+        assert_eq!(info.tile_size, (512, 256));
+        assert_eq!(info.normalization.apply(1.0), 1.0);
+        assert_eq!(info.threshold, 0.5);
+
+        // the declared input shape wins
+        let info = ModelInfo::from_bytes(&model(
+            vec![fixed(1), fixed(3), fixed(640), fixed(480)],
+            &metadata,
+        ))
+        .unwrap();
+        assert_eq!(info.tile_size, (640, 480));
+
+        assert!(ModelInfo::from_bytes(&model(vec![], &[("threshold", "high")])).is_err());
+    }
+}
@@ -1,14 +1,20 @@
 use cfg_if::cfg_if;
 
+mod info;
+
+pub use info::ModelInfo;
+
+// parameters of the original model, used for the models which don't specify their own
 pub const BATCH_WIDTH: usize = 828;
 pub const BATCH_HEIGHT: usize = 1176;
-pub const MODEL_INPUT_SHAPE: [usize; 4] = [1, 3, BATCH_HEIGHT, BATCH_WIDTH];
-pub const MODEL_OUTPUT_SHAPE: [usize; 4] = [1, 1, BATCH_HEIGHT, BATCH_WIDTH];
 
 pub const THRESHOLD: f32 = 0.0005;
 
 cfg_if!(
-    if #[cfg(feature = "onnxruntime-backend")] {
+    if #[cfg(test)] {
+        mod stub;
+        pub use self::stub::*;
+    } else if #[cfg(feature = "onnxruntime-backend")] {
         mod onnxruntime;
         pub use self::onnxruntime::*;
     } else if #[cfg(feature = "tract-backend")] {
@@ -1,4 +1,3 @@
-use super::MODEL_OUTPUT_SHAPE;
 use anyhow::Result;
 use ndarray::prelude::*;
 use once_cell::sync::Lazy;
@@ -18,11 +17,14 @@ static ENVIRONMENT: Lazy<Environment> = Lazy::new(|| {
 });
 
 pub struct Model {
-    session: Mutex<Session<'static>>,
+    /// Kept around to create more sessions when several batches are processed in parallel
+    bytes: Vec<u8>,
+    /// Sessions that are not running anything right now
+    idle_sessions: Mutex<Vec<Session<'static>>>,
 }
 
 impl Model {
-    pub fn new_from_bytes<B: AsRef<[u8]>>(bytes: B) -> Result<Self> {
+    fn new_session(bytes: &[u8]) -> Result<Session<'static>> {
         info!("Creating inference session with onnxruntime");
 
         let session = ENVIRONMENT
@@ -31,22 +33,49 @@ impl Model {
             // TODO: threads??
             // .with_intra_op_num_threads(1)?
             .with_model_from_memory(bytes)?;
-        let session = Mutex::new(session);
 
-        Ok(Self { session })
+        Ok(session)
+    }
+
+    pub fn new_from_bytes<B: AsRef<[u8]>>(bytes: B) -> Result<Self> {
+        let bytes = bytes.as_ref().to_vec();
+        let session = Self::new_session(&bytes)?;
+
+        Ok(Self {
+            bytes,
+            idle_sessions: Mutex::new(vec![session]),
+        })
     }
 
-    pub fn run_model(&self, image_buf: Array4<f32>) -> Array4<f32> {
+    pub fn run_model(&self, image_buf: Array4<f32>) -> Result<Array4<f32>> {
         let mut image_buf = onnxruntime::session::NdArray::new(image_buf);
         let input_tensor: Vec<&mut dyn AnyArray> = vec![&mut image_buf];
 
-        let model_output = {
-            let mut session = self.session.lock().unwrap();
-            let model_output = session.run(input_tensor).unwrap();
+        // a poisoned lock only means that some other inference panicked, the sessions list itself is fine
+        let idle_session = self
+            .idle_sessions
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .pop();
+        let mut session = match idle_session {
+            Some(session) => session,
+            None => Self::new_session(&self.bytes)?,
+        };
 
-            let model_output = model_output.into_iter().next().unwrap().deref().to_owned();
+        let model_output = session.run(input_tensor).map(|model_output| {
             model_output
-        };
-        model_output.into_shape(MODEL_OUTPUT_SHAPE).unwrap()
+                .into_iter()
+                .next()
+                .map(|output| output.deref().to_owned())
+        });
+
+        self.idle_sessions
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(session);
+
+        let model_output =
+            model_output?.ok_or_else(|| anyhow::anyhow!("model returned no outputs"))?;
+        Ok(model_output.into_dimensionality::<Ix4>()?)
     }
 }
@@ -1,4 +1,3 @@
-use super::MODEL_OUTPUT_SHAPE;
 use anyhow::Result;
 use ndarray::prelude::*;
 use std::io;
@@ -28,15 +27,17 @@ impl Model {
         Ok(Self { model })
     }
 
-    pub fn run_model(&self, image_buf: Array4<f32>) -> Array4<f32> {
+    pub fn run_model(&self, image_buf: Array4<f32>) -> Result<Array4<f32>> {
         let image_buf = image_buf.into();
-        let model_output = self.model.run(tvec!(image_buf)).unwrap();
-        let model_output = model_output.into_iter().next().unwrap();
-        Arc::try_unwrap(model_output)
-            .unwrap()
-            .into_array::<f32>()
-            .unwrap()
-            .into_shape(MODEL_OUTPUT_SHAPE)
-            .unwrap()
+        let model_output = self.model.run(tvec!(image_buf))?;
+        let model_output = model_output
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("model returned no outputs"))?;
+        let model_output = Arc::try_unwrap(model_output)
+            .unwrap_or_else(|arc| (*arc).clone())
+            .into_array::<f32>()?
+            .into_dimensionality::<Ix4>()?;
+        Ok(model_output)
     }
 }
@@ -1,4 +1,4 @@
-use crate::{ProgressKind, ProgressReporter};
+use crate::{MangaiError, ProgressKind, ProgressReporter};
 use anyhow::Result;
 use sha2::Digest;
 use std::path::PathBuf;
@@ -94,6 +94,11 @@ pub fn get_model(progress: &mut dyn ProgressReporter) -> Result<Vec<u8>> {
     let mut total_read = 0;
     let mut prev_progress = Instant::now();
     loop {
+        if progress.is_cancelled() {
+            info!("model download cancelled");
+            return Err(MangaiError::Cancelled.into());
+        }
+
         let read = reader.read(&mut buffer)?;
         if read == 0 {
             break;
@@ -0,0 +1,306 @@
+use crate::error::{MangaiError, Result};
+use ndarray::Array2;
+
+/// Shape of the structuring element used to dilate the text mask
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelShape {
+    /// Full `(2r+1)x(2r+1)` square
+    Square,
+    /// Plus-shaped kernel (only the central row and column are set)
+    Cross,
+    /// Disk inscribed into the `(2r+1)x(2r+1)` square
+    Ellipse,
+}
+
+impl KernelShape {
+    pub fn kernel(&self, radius: usize) -> Array2<bool> {
+        let size = radius * 2 + 1;
+        let r = radius as isize;
+        Array2::from_shape_fn((size, size), |(y, x)| {
+            let dy = y as isize - r;
+            let dx = x as isize - r;
+            match self {
+                KernelShape::Square => true,
+                KernelShape::Cross => dy == 0 || dx == 0,
+                KernelShape::Ellipse => dy * dy + dx * dx <= r * r,
+            }
+        })
+    }
+}
+
+/// How the model outputs of overlapping batches are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendMode {
+    /// Threshold and dilate each batch separately, then OR the resulting masks
+    Or,
+    /// Take the maximum probability over all the batches covering a pixel
+    Max,
+    /// Take the mean probability over all the batches covering a pixel
+    Average,
+    /// Weighted mean, where pixels near the batch edges (which lack context) get less weight
+    EdgeWeighted,
+}
+
+/// What to put in place of the detected text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FillMode {
+    /// Paint everything with [`CleanOptions::fill_value`]
+    Solid,
+    /// Paint each connected region with the median color of the unmasked pixels within `ring_width` around it
+    ///
+    /// Handy for off-white paper and grey bubbles
+    Background { ring_width: usize },
+    /// Inpaint the regions with Telea's fast marching method, looking at most `radius` pixels away
+    ///
+    /// Smoothly continues gradients and soft shading, but blurs any texture. `radius` must be at least 1
+    Telea { radius: usize },
+    /// Synthesize the regions from similar `(2 * patch_radius + 1)` sized patches found at most `search_radius`
+    /// pixels away
+    ///
+    /// Slower than the other modes, but keeps textures like screentone and hatching
+    PatchMatch {
+        patch_radius: usize,
+        search_radius: usize,
+    },
+    /// Detect a periodic halftone pattern within `ring_width` around each region and continue it inside
+    ///
+    /// Patterns with a period over `max_period` pixels are not detected, such regions (and the ones on plain paper)
+    /// are filled like with [`FillMode::Background`]
+    Screentone {
+        ring_width: usize,
+        max_period: usize,
+    },
+}
+
+impl FillMode {
+    /// Fails with [`MangaiError::UnsupportedOption`] for the parameters which would leave the text as is
+    pub(crate) fn check(&self) -> Result<()> {
+        match *self {
+            FillMode::Telea { radius: 0 } => Err(MangaiError::UnsupportedOption {
+                option: "Telea fill with a radius of 0",
+                operation: "cleaning",
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// How far from a masked pixel the fill may look at the page
+    pub(crate) fn reach(&self) -> usize {
+        match *self {
+            FillMode::Solid => 0,
+            FillMode::Background { ring_width } => ring_width,
+            FillMode::Telea { radius } => radius + 1,
+            // starts from a Telea fill with `patch_radius + 1`, then copies the patches from `search_radius` away
+            FillMode::PatchMatch {
+                patch_radius,
+                search_radius,
+            } => patch_radius + search_radius.max(2),
+            FillMode::Screentone {
+                ring_width,
+                max_period,
+            } => ring_width + max_period,
+        }
+    }
+}
+
+/// Parameters of the speech bubble detection, see [`CleanOptions::bubbles`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct BubbleOptions {
+    /// Pixels with the mean channel value at or above this are considered to be the inside of a bubble
+    ///
+    /// In the 8-bit scale, whatever the sample type of the page is
+    pub light_threshold: u8,
+    /// Bubbles with more than `max_growth` times the area of the text inside are assumed to be a leak into the
+    /// background (like an empty panel), and are not filled
+    pub max_growth: f32,
+}
+
+impl Default for BubbleOptions {
+    fn default() -> Self {
+        Self {
+            light_threshold: 200,
+            max_growth: 10.0,
+        }
+    }
+}
+
+/// Clean-up of the thresholded mask before the dilation, see [`CleanOptions::post_processing`]
+///
+/// The steps are applied in the order of the fields, `0` disables a step
+#[derive(Debug, Clone, PartialEq)]
+pub struct PostProcessingOptions {
+    /// Radius of the morphological opening (erosion, then dilation), removing specks and lines thinner than the kernel
+    pub opening_radius: usize,
+    /// Connected regions of fewer pixels than this are dropped, unless they touch the edge of the processed area
+    pub min_area: usize,
+    /// Radius of the morphological closing (dilation, then erosion), joining strokes closer than the kernel size
+    pub closing_radius: usize,
+    /// Holes in the mask (like the inside of an "o") of fewer pixels than this are filled
+    pub max_hole_area: usize,
+    /// Shape of the opening and closing kernels
+    pub kernel_shape: KernelShape,
+}
+
+impl Default for PostProcessingOptions {
+    fn default() -> Self {
+        Self {
+            opening_radius: 0,
+            min_area: 10,
+            closing_radius: 2,
+            max_hole_area: 400,
+            kernel_shape: KernelShape::Ellipse,
+        }
+    }
+}
+
+/// Scale of the page relative to the one the model works best at
+///
+/// The page is resampled by `target / page` before running the model, the detected mask is resized back
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resolution {
+    /// The page is scanned at `page` DPI, the model works best at `target` DPI
+    Dpi { page: f32, target: f32 },
+    /// The text on the page is about `page` pixels high, the model works best with `target` pixels high text
+    TextHeight { page: f32, target: f32 },
+}
+
+impl Resolution {
+    pub fn scale(&self) -> f32 {
+        match *self {
+            Resolution::Dpi { page, target } | Resolution::TextHeight { page, target } => {
+                target / page
+            }
+        }
+    }
+}
+
+/// Knobs controlling how aggressively the page is cleaned
+///
+/// The default values reproduce the original hardcoded behaviour
+#[derive(Debug, Clone, PartialEq)]
+pub struct CleanOptions {
+    /// Model output values above this are considered text, `None` uses the threshold the model comes with
+    pub threshold: Option<f32>,
+    /// Radius of the dilation kernel (1 means 3x3)
+    pub dilation_radius: usize,
+    /// How many times the dilation is applied
+    pub dilation_iterations: usize,
+    pub kernel_shape: KernelShape,
+    /// Value written to the masked pixels, in the 8-bit scale (`255` is white for any sample type)
+    ///
+    /// Also used as a fallback by the other fill modes
+    pub fill_value: u8,
+    pub fill_mode: FillMode,
+    pub blend_mode: BlendMode,
+    /// Number of threads running the model on different batches at once
+    ///
+    /// `1` processes the batches sequentially on the calling thread
+    pub num_workers: usize,
+    /// Maximum number of batches stacked into a single model invocation
+    ///
+    /// Only has effect if the model has a dynamic batch axis, otherwise the batches are always run one by one
+    pub max_batch_size: usize,
+    /// Minimal distance of each pixel from the inner edges of some batch, so that the model sees enough context around it
+    ///
+    /// The neighbouring batches overlap by at least twice this. It must be less than half of the model tile size, or
+    /// the cleaning fails with [`MangaiError::TileMarginTooLarge`](crate::MangaiError::TileMarginTooLarge). `0` covers
+    /// the page with as few batches as possible
+    pub tile_margin: usize,
+    /// Number of extra passes of each batch through the model, flipped horizontally, then rotated by 90, 270 and 180
+    /// degrees (up to 4)
+    ///
+    /// Helps with vertical text, rotated sound effects and text at the batch edges. The outputs of all the passes are
+    /// merged by taking the maximum, so this can only grow the mask. Each pass takes about as long as the plain one, the
+    /// 90 degree rotations twice as long unless the model takes square tiles
+    pub augmentations: usize,
+    /// Clean up the thresholded mask before it's dilated, `None` uses it as is
+    ///
+    /// With [`BlendMode::Or`] each batch is processed separately, otherwise the whole page at once (which streaming
+    /// cleaning doesn't support)
+    pub post_processing: Option<PostProcessingOptions>,
+    /// Clean the whole interior of the speech bubbles around the text, up to their outlines
+    ///
+    /// Text without a closed bubble around it is cleaned with the usual mask. `None` disables the detection
+    pub bubbles: Option<BubbleOptions>,
+    /// Also run the model on the inverted page to find light text on dark background (like narration boxes)
+    ///
+    /// Doubles the processing time. Such text is filled with the color around it instead of [`Self::fill_mode`]
+    pub detect_inverted: bool,
+    /// Run the model on the page resampled to this resolution, `None` runs it on the page as is
+    ///
+    /// The dilation is applied at the model scale, the fill is applied to the original pixels. A scale which isn't finite
+    /// and positive fails with [`MangaiError::InvalidScale`](crate::MangaiError::InvalidScale), one making the page too
+    /// large with [`MangaiError::ScaledPageTooLarge`](crate::MangaiError::ScaledPageTooLarge)
+    pub resolution: Option<Resolution>,
+}
+
+impl Default for CleanOptions {
+    fn default() -> Self {
+        Self {
+            threshold: None,
+            dilation_radius: 1,
+            dilation_iterations: 2,
+            kernel_shape: KernelShape::Square,
+            fill_value: 255,
+            fill_mode: FillMode::Solid,
+            blend_mode: BlendMode::Or,
+            num_workers: 1,
+            max_batch_size: 1,
+            tile_margin: 0,
+            augmentations: 0,
+            post_processing: None,
+            bubbles: None,
+            detect_inverted: false,
+            resolution: None,
+        }
+    }
+}
+
+impl CleanOptions {
+    pub(crate) fn dilation_kernel(&self) -> Array2<bool> {
+        self.kernel_shape.kernel(self.dilation_radius)
+    }
+
+    /// How far the dilation can grow the mask from a pixel above the threshold
+    pub(crate) fn dilation_reach(&self) -> usize {
+        self.dilation_radius * self.dilation_iterations
+    }
+
+    /// How much to scale the page by before running the model
+    pub(crate) fn model_scale(&self) -> f32 {
+        self.resolution.map_or(1.0, |resolution| resolution.scale())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_default_kernel_is_3x3_square() {
+        let kernel = CleanOptions::default().dilation_kernel();
+        assert_eq!(kernel, Array2::from_elem((3, 3), true));
+    }
+
+    #[test]
+    fn test_kernel_shapes() {
+        assert_eq!(
+            KernelShape::Cross.kernel(1),
+            ndarray::arr2(&[
+                [false, true, false],
+                [true, true, true],
+                [false, true, false]
+            ])
+        );
+        assert_eq!(
+            KernelShape::Ellipse.kernel(2),
+            ndarray::arr2(&[
+                [false, false, true, false, false],
+                [false, true, true, true, false],
+                [true, true, true, true, true],
+                [false, true, true, true, false],
+                [false, false, true, false, false],
+            ])
+        );
+    }
+}
@@ -0,0 +1,172 @@
+//! Adapting pages of any supported channel layout to the model
+//!
+//! Pages are passed around in (channels, height, width) layout, grayscale ones having a single channel. The alpha
+//! channel, if any, goes last
+
+use crate::error::{MangaiError, Result};
+use crate::sample::Sample;
+use ndarray::{
+    s, Array2, Array3, ArrayBase, ArrayView2, ArrayView3, ArrayViewMut3, Axis, CowArray, Data, Ix3,
+    Zip,
+};
+use tracing::info;
+
+/// The model takes RGB input
+const MODEL_CHANNELS: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Gray,
+    GrayAlpha,
+    Rgb,
+    RgbAlpha,
+}
+
+impl Layout {
+    /// Figures out the layout from the number of channels of the page
+    pub fn of<T>(image: ArrayView3<T>) -> Result<Self> {
+        let (channels, _, _) = image.dim();
+        Self::from_channels(channels)
+    }
+
+    pub fn from_channels(channels: usize) -> Result<Self> {
+        match channels {
+            1 => Ok(Self::Gray),
+            2 => Ok(Self::GrayAlpha),
+            3 => Ok(Self::Rgb),
+            4 => Ok(Self::RgbAlpha),
+            channels => Err(MangaiError::UnsupportedChannels { channels }),
+        }
+    }
+
+    pub fn color_channels(self) -> usize {
+        match self {
+            Self::Gray | Self::GrayAlpha => 1,
+            Self::Rgb | Self::RgbAlpha => MODEL_CHANNELS,
+        }
+    }
+
+    pub fn has_alpha(self) -> bool {
+        matches!(self, Self::GrayAlpha | Self::RgbAlpha)
+    }
+
+    /// The page without the alpha channel
+    pub fn colors<'a, T>(self, image: ArrayView3<'a, T>) -> ArrayView3<'a, T> {
+        image.slice_move(s![..self.color_channels(), .., ..])
+    }
+
+    pub fn colors_mut<'a, T>(self, image: ArrayViewMut3<'a, T>) -> ArrayViewMut3<'a, T> {
+        image.slice_move(s![..self.color_channels(), .., ..])
+    }
+
+    pub fn alpha<'a, T>(self, image: ArrayView3<'a, T>) -> Option<ArrayView2<'a, T>> {
+        self.has_alpha()
+            .then(|| image.index_axis_move(Axis(0), self.color_channels()))
+    }
+
+    /// Pixels which are not fully transparent, `None` if the page has no alpha channel
+    pub fn opaque<T: Sample>(self, image: ArrayView3<T>) -> Option<Array2<bool>> {
+        self.alpha(image)
+            .map(|alpha| alpha.mapv(|a| a.to_unit() > 0.0))
+    }
+
+    /// The color channels, as the model should see them: fully transparent pixels are blank paper
+    pub fn visible_colors<T: Sample>(self, image: ArrayView3<'_, T>) -> CowArray<'_, T, Ix3> {
+        let colors = self.colors(image);
+        match self.opaque(image) {
+            Some(opaque) => {
+                let mut colors = colors.to_owned();
+                let opaque = opaque.broadcast(colors.dim()).unwrap();
+                Zip::from(&mut colors)
+                    .and(opaque)
+                    .for_each(|value, &opaque| {
+                        if !opaque {
+                            *value = T::WHITE;
+                        }
+                    });
+                CowArray::from(colors)
+            }
+            None => CowArray::from(colors),
+        }
+    }
+}
+
+/// Pads the page with white if it's smaller than a single batch of `tile_size` (height, width)
+pub fn pad<T: Sample>(
+    image_in: ArrayView3<'_, T>,
+    tile_size: (usize, usize),
+) -> CowArray<'_, T, Ix3> {
+    let (channels, orig_height, orig_width) = image_in.dim();
+    let (tile_height, tile_width) = tile_size;
+
+    if orig_height < tile_height || orig_width < tile_width {
+        let height = tile_height.max(orig_height);
+        let width = tile_width.max(orig_width);
+
+        info!(
+            "Padding the image to fit the batch size (padded size is {}x{})",
+            width, height
+        );
+        let mut padded_image = Array3::from_elem((channels, height, width), T::WHITE);
+        padded_image
+            .slice_mut(s![.., ..orig_height, ..orig_width])
+            .assign(&image_in);
+
+        CowArray::from(padded_image)
+    } else {
+        CowArray::from(image_in)
+    }
+}
+
+/// Presents the color channels of a page as RGB, without copying
+pub fn model_channels<S: Data>(image: &ArrayBase<S, Ix3>) -> ArrayView3<'_, S::Elem> {
+    let (_, height, width) = image.dim();
+    // a single channel can always be broadcast to the three
+    image.broadcast((MODEL_CHANNELS, height, width)).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{BATCH_HEIGHT, BATCH_WIDTH};
+
+    #[test]
+    fn test_pad_tall_narrow_page() {
+        let image = Array3::from_elem((1, BATCH_HEIGHT + 10, 5), 0u8);
+        let padded = pad(image.view(), (BATCH_HEIGHT, BATCH_WIDTH));
+
+        assert_eq!(padded.dim(), (1, BATCH_HEIGHT + 10, BATCH_WIDTH));
+        assert_eq!(padded[[0, BATCH_HEIGHT + 9, 4]], 0);
+        assert_eq!(padded[[0, BATCH_HEIGHT + 9, 5]], 255);
+    }
+
+    #[test]
+    fn test_model_channels() {
+        let image = Array3::from_shape_fn((1, 2, 2), |(_, y, x)| (y * 2 + x) as u8);
+        let rgb = model_channels(&image);
+
+        assert_eq!(rgb.dim(), (3, 2, 2));
+        assert_eq!(
+            rgb.index_axis(ndarray::Axis(0), 2),
+            image.index_axis(ndarray::Axis(0), 0)
+        );
+        assert!(Layout::of(Array3::<u8>::zeros((5, 1, 1)).view()).is_err());
+    }
+
+    #[test]
+    fn test_alpha() {
+        let mut image = Array3::from_elem((2, 2, 2), 10u8);
+        image[[1, 0, 0]] = 0;
+        let layout = Layout::of(image.view()).unwrap();
+
+        assert_eq!(layout, Layout::GrayAlpha);
+        assert_eq!(
+            layout.opaque(image.view()),
+            Some(ndarray::arr2(&[[false, true], [true, true]]))
+        );
+        assert_eq!(
+            layout.visible_colors(image.view()),
+            ndarray::arr3(&[[[255, 10], [10, 10]]])
+        );
+    }
+}
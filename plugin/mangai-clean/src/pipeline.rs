@@ -0,0 +1,483 @@
+use crate::batcher::Batcher;
+use crate::page;
+use crate::sample::Sample;
+use crate::{MangaiError, ProgressKind, ProgressReporter, Result};
+use ndarray::{s, ArrayView2, ArrayView3, CowArray, Ix3, SliceInfo, SliceInfoElem};
+use std::collections::BTreeMap;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Condvar, Mutex};
+use tracing::info;
+
+type BatchSlice = SliceInfo<[SliceInfoElem; 3], Ix3, Ix3>;
+
+/// Something the batches can be cut from, like an (already padded) page
+pub trait TileSource<S>: Sync {
+    /// (channels, height, width) of the page, which must be at least the size of a batch in each dimension
+    fn dim(&self) -> (usize, usize, usize);
+
+    /// Returns the tile of `size` at `origin`, both given as (row, column), either grayscale or RGB
+    fn tile(&self, origin: (usize, usize), size: (usize, usize)) -> CowArray<'_, S, Ix3>;
+
+    /// Whether the tile needs to be run through the model at all, the skipped tiles are treated as having no text
+    fn is_needed(&self, _origin: (usize, usize), _size: (usize, usize)) -> bool {
+        true
+    }
+}
+
+impl<'a, S: Sync> TileSource<S> for ArrayView3<'a, S> {
+    fn dim(&self) -> (usize, usize, usize) {
+        ArrayView3::dim(self)
+    }
+
+    fn tile(
+        &self,
+        (top, left): (usize, usize),
+        (height, width): (usize, usize),
+    ) -> CowArray<'_, S, Ix3> {
+        CowArray::from(self.slice(s![.., top..top + height, left..left + width]))
+    }
+}
+
+/// Padded (and possibly resampled) page, with only the tiles overlapping the include mask of the original page needed
+pub struct IncludedTiles<'a, 'b, S> {
+    pub image: ArrayView3<'a, S>,
+    /// (height, width) of the image before the padding
+    pub unpadded_size: (usize, usize),
+    /// At the original page size, `None` needs all the tiles
+    pub include_mask: Option<ArrayView2<'b, bool>>,
+}
+
+impl<'a, 'b, S: Sync> TileSource<S> for IncludedTiles<'a, 'b, S> {
+    fn dim(&self) -> (usize, usize, usize) {
+        self.image.dim()
+    }
+
+    fn tile(&self, origin: (usize, usize), size: (usize, usize)) -> CowArray<'_, S, Ix3> {
+        self.image.tile(origin, size)
+    }
+
+    fn is_needed(&self, (top, left): (usize, usize), (height, width): (usize, usize)) -> bool {
+        let Some(include_mask) = &self.include_mask else {
+            return true;
+        };
+
+        // the part of the original page the tile covers, the padding covers none
+        let (page_height, page_width) = include_mask.dim();
+        let (unpadded_height, unpadded_width) = self.unpadded_size;
+        let page_range = |start: usize, len: usize, unpadded_len: usize, page_len: usize| {
+            let end = (start + len).min(unpadded_len);
+            (start * page_len / unpadded_len).min(page_len)..(end * page_len).div_ceil(unpadded_len)
+        };
+        let rows = page_range(top, height, unpadded_height, page_height);
+        let columns = page_range(left, width, unpadded_width, page_width);
+
+        rows.start < rows.end
+            && columns.start < columns.end
+            && include_mask
+                .slice(s![rows, columns])
+                .iter()
+                .any(|&included| included)
+    }
+}
+
+/// Returns the (top, left) corner of the batch on the page
+pub fn batch_origin(slice: &BatchSlice) -> (usize, usize) {
+    match [slice.deref()[1], slice.deref()[2]] {
+        [SliceInfoElem::Slice { start: top, .. }, SliceInfoElem::Slice { start: left, .. }] => {
+            (top as usize, left as usize)
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Runs `process` over all the batches of an (already padded) page
+///
+/// The batches are passed to `process` in groups of up to `group_size`, so that they can be stacked into a single
+/// inference call. `process` may be called from `num_workers` threads at once, while `consume` is always called on the
+/// calling thread and in the batch order, so the end result does not depend on the number of workers or the group size.
+/// The workers don't get more than `num_workers` groups ahead of `consume`, so the results waiting for their turn stay
+/// bounded. The batches go row by row, so once `consume` gets a batch, no later batch starts above it
+///
+/// The batches are `tile_size` (height, width), and each pixel is at least `margin` pixels away from the inner edges of
+/// some batch, see [`Batcher`]. The tiles the page doesn't [`TileSource::is_needed`] are skipped. Grayscale tiles are passed to
+/// `process` as RGB
+#[allow(clippy::too_many_arguments)]
+pub fn run_batches<S: Sample, T: Send>(
+    image_in: &impl TileSource<S>,
+    num_workers: usize,
+    group_size: usize,
+    tile_size: (usize, usize),
+    margin: usize,
+    progress_reporter: &mut dyn ProgressReporter,
+    process: impl Fn(&[ArrayView3<S>]) -> Result<Vec<T>> + Sync,
+    mut consume: impl FnMut(T, (usize, usize)) -> Result<()>,
+) -> Result<()> {
+    let (_, height, width) = image_in.dim();
+
+    let batcher = Batcher::new(height, width, tile_size, margin)?;
+    let slices = batcher
+        .iter()
+        .filter(|slice| image_in.is_needed(batch_origin(slice), tile_size))
+        .collect::<Vec<_>>();
+    let num_batches = slices.len();
+    if num_batches < batcher.num_batches() {
+        info!(
+            "Skipping {} batches outside of the included region",
+            batcher.num_batches() - num_batches
+        );
+    }
+    progress_reporter.init(ProgressKind::Items, "Cleaning manga", num_batches);
+
+    let groups = slices.chunks(group_size.max(1)).collect::<Vec<_>>();
+
+    let process_group = |group: &[BatchSlice]| {
+        let tiles = group
+            .iter()
+            .map(|slice| image_in.tile(batch_origin(slice), tile_size))
+            .collect::<Vec<_>>();
+        let images = tiles.iter().map(page::model_channels).collect::<Vec<_>>();
+        let results = process(&images)?;
+        MangaiError::check_shape("processed group", &[group.len()], &[results.len()])?;
+        Ok(results)
+    };
+
+    // consumes the results of a group, returns the number of batches done after it
+    let mut consume_group = |group: &[BatchSlice], results: Vec<T>, mut done: usize| {
+        for (slice, result) in group.iter().zip(results) {
+            info!("Finished batch #{}/{}", done + 1, num_batches);
+            consume(result, batch_origin(slice))?;
+            done += 1;
+        }
+        Ok(done)
+    };
+
+    if num_workers <= 1 {
+        let mut done = 0;
+        for group in &groups {
+            if progress_reporter.is_cancelled() {
+                info!("Cancelled at batch #{}/{}", done + 1, num_batches);
+                return Err(MangaiError::Cancelled);
+            }
+            progress_reporter.progress(done);
+
+            let results = process_group(group)?;
+            done = consume_group(group, results, done)?;
+        }
+    } else {
+        let next_group = AtomicUsize::new(0);
+        let stop = AtomicBool::new(false);
+        // index of the group the consumer waits for, the workers don't start the groups `num_workers` past it
+        let next_to_consume = Mutex::new(0);
+        let consumed = Condvar::new();
+
+        info!(
+            "Processing {} batches in {} groups with {} workers",
+            num_batches,
+            groups.len(),
+            num_workers
+        );
+
+        std::thread::scope(|scope| {
+            let (tx, rx) = mpsc::sync_channel(num_workers);
+
+            for _ in 0..num_workers {
+                let tx = tx.clone();
+                let (groups, next_group, stop, process_group) =
+                    (&groups, &next_group, &stop, &process_group);
+                let (next_to_consume, consumed) = (&next_to_consume, &consumed);
+                scope.spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        let i = next_group.fetch_add(1, Ordering::Relaxed);
+                        let group = match groups.get(i) {
+                            Some(group) => group,
+                            None => break,
+                        };
+
+                        // so that at most `num_workers` groups wait for the consumer, however slow the first of them is
+                        let mut next = next_to_consume.lock().unwrap();
+                        while i >= *next + num_workers && !stop.load(Ordering::Relaxed) {
+                            next = consumed.wait(next).unwrap();
+                        }
+                        drop(next);
+                        if stop.load(Ordering::Relaxed) {
+                            break;
+                        }
+
+                        let results = process_group(group);
+                        if tx.send((i, results)).is_err() {
+                            // the consumer has bailed out
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(tx);
+
+            let result = (|| {
+                // groups can be finished out of order, so keep them until their turn comes
+                let mut pending = BTreeMap::new();
+                let mut next = 0;
+                let mut done = 0;
+                for (i, results) in &rx {
+                    pending.insert(i, results);
+
+                    while let Some(results) = pending.remove(&next) {
+                        if progress_reporter.is_cancelled() {
+                            info!("Cancelled at batch #{}/{}", done + 1, num_batches);
+                            return Err(MangaiError::Cancelled);
+                        }
+
+                        done = consume_group(groups[next], results?, done)?;
+                        next += 1;
+                        *next_to_consume.lock().unwrap() = next;
+                        consumed.notify_all();
+                        progress_reporter.progress(done);
+                    }
+                }
+                Ok(())
+            })();
+
+            // make the workers exit early if something went wrong, the waiting ones too
+            {
+                let _next = next_to_consume.lock().unwrap();
+                stop.store(true, Ordering::Relaxed);
+            }
+            consumed.notify_all();
+            drop(rx);
+
+            result
+        })?;
+    }
+
+    progress_reporter.finish();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ndarray::{Array2, Array3};
+    use std::time::Duration;
+
+    /// Cancels after being polled `cancel_after` times, if at all
+    struct Reporter {
+        cancel_after: Option<usize>,
+        polls: usize,
+        total: usize,
+    }
+
+    impl Reporter {
+        fn new(cancel_after: Option<usize>) -> Self {
+            Self {
+                cancel_after,
+                polls: 0,
+                total: 0,
+            }
+        }
+    }
+
+    impl ProgressReporter for Reporter {
+        fn init(&mut self, _kind: ProgressKind, _operation: &str, total: usize) {
+            self.total = total;
+        }
+
+        fn progress(&mut self, _progress: usize) {}
+
+        fn finish(&mut self) {}
+
+        fn is_cancelled(&mut self) -> bool {
+            self.polls += 1;
+            self.cancel_after.is_some_and(|n| self.polls > n)
+        }
+    }
+
+    /// A page whose pixels encode their position, so the tiles tell where they are from
+    fn page() -> Array3<u16> {
+        Array3::from_shape_fn((1, 80, 30), |(_, y, x)| (y * 100 + x) as u16)
+    }
+
+    const TILE_SIZE: (usize, usize) = (8, 8);
+
+    /// Returns the origins of the tiles, taking longer for some of them so the workers finish out of order
+    fn process(images_in: &[ArrayView3<u16>]) -> Result<Vec<(usize, usize)>> {
+        Ok(images_in
+            .iter()
+            .map(|image_in| {
+                let corner = image_in[[0, 0, 0]] as usize;
+                let origin = (corner / 100, corner % 100);
+                std::thread::sleep(Duration::from_millis(((origin.0 + origin.1) % 3) as u64));
+                origin
+            })
+            .collect())
+    }
+
+    fn run(
+        num_workers: usize,
+        group_size: usize,
+        reporter: &mut Reporter,
+        process: impl Fn(&[ArrayView3<u16>]) -> Result<Vec<(usize, usize)>> + Sync,
+    ) -> (Result<()>, Vec<(usize, usize)>) {
+        let page = page();
+        let mut consumed = vec![];
+        let result = run_batches(
+            &page.view(),
+            num_workers,
+            group_size,
+            TILE_SIZE,
+            0,
+            reporter,
+            process,
+            |result, origin| {
+                // each result gets to its own batch
+                assert_eq!(result, origin);
+                consumed.push(origin);
+                Ok(())
+            },
+        );
+        (result, consumed)
+    }
+
+    #[test]
+    fn test_parallel_order() {
+        let (result, expected) = run(1, 1, &mut Reporter::new(None), process);
+        result.unwrap();
+        assert_eq!(expected.len(), 10 * 4);
+
+        for num_workers in [2, 3, 8] {
+            for group_size in [1, 3] {
+                let (result, consumed) =
+                    run(num_workers, group_size, &mut Reporter::new(None), process);
+                result.unwrap();
+                assert_eq!(consumed, expected, "{} {}", num_workers, group_size);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parallel_error() {
+        for num_workers in [1, 2, 3, 8] {
+            let calls = AtomicUsize::new(0);
+            let failing = |images_in: &[ArrayView3<u16>]| {
+                calls.fetch_add(1, Ordering::Relaxed);
+                let origins = process(images_in)?;
+                if origins.contains(&(8, 7)) {
+                    return Err(MangaiError::Inference(anyhow::anyhow!("failed")));
+                }
+                // slow enough that the failure is noticed before the workers are through
+                std::thread::sleep(Duration::from_millis(20));
+                Ok(origins)
+            };
+
+            let mut reporter = Reporter::new(None);
+            let (result, consumed) = run(num_workers, 1, &mut reporter, failing);
+            assert!(matches!(result, Err(MangaiError::Inference(_))));
+            // the batches before the failing one are consumed in order, none after it
+            assert_eq!(consumed.last(), Some(&(8, 0)), "{}", num_workers);
+            assert!(calls.into_inner() < reporter.total, "{}", num_workers);
+        }
+    }
+
+    #[test]
+    fn test_parallel_cancel() {
+        for num_workers in [1, 2, 3, 8] {
+            let calls = AtomicUsize::new(0);
+            let slow = |images_in: &[ArrayView3<u16>]| {
+                calls.fetch_add(1, Ordering::Relaxed);
+                std::thread::sleep(Duration::from_millis(20));
+                process(images_in)
+            };
+
+            let mut reporter = Reporter::new(Some(3));
+            let (result, consumed) = run(num_workers, 1, &mut reporter, slow);
+            assert!(matches!(result, Err(MangaiError::Cancelled)));
+            assert_eq!(consumed.len(), 3, "{}", num_workers);
+            assert!(calls.into_inner() < reporter.total, "{}", num_workers);
+        }
+    }
+
+    #[test]
+    fn test_parallel_bounded() {
+        for num_workers in [2, 3, 8] {
+            let processed = AtomicUsize::new(0);
+            let first_slow = |images_in: &[ArrayView3<u16>]| {
+                let origins = process(images_in)?;
+                if origins.contains(&(0, 0)) {
+                    std::thread::sleep(Duration::from_millis(200));
+                }
+                processed.fetch_add(1, Ordering::Relaxed);
+                Ok(origins)
+            };
+
+            let page = page();
+            let mut consumed = 0;
+            let mut max_waiting = 0;
+            run_batches(
+                &page.view(),
+                num_workers,
+                1,
+                TILE_SIZE,
+                0,
+                &mut Reporter::new(None),
+                first_slow,
+                |_, _| {
+                    let waiting = processed.load(Ordering::Relaxed) - consumed;
+                    max_waiting = max_waiting.max(waiting);
+                    consumed += 1;
+                    Ok(())
+                },
+            )
+            .unwrap();
+
+            assert_eq!(consumed, 40);
+            assert!(
+                max_waiting <= num_workers,
+                "{} {}",
+                num_workers,
+                max_waiting
+            );
+        }
+    }
+
+    #[test]
+    fn test_consume_error() {
+        let page = page();
+        let result = run_batches(
+            &page.view(),
+            3,
+            1,
+            TILE_SIZE,
+            0,
+            &mut Reporter::new(None),
+            process,
+            |_, origin| match origin {
+                (8, 0) => Err(MangaiError::UnsupportedChannels { channels: 0 }),
+                _ => Ok(()),
+            },
+        );
+        assert!(matches!(
+            result,
+            Err(MangaiError::UnsupportedChannels { channels: 0 })
+        ));
+    }
+
+    #[test]
+    fn test_included_tiles() {
+        // the page was scaled down to half, and padded from 4x4 to 6x6
+        let image = Array3::<u8>::zeros((1, 6, 6));
+        let mut include_mask = Array2::from_elem((8, 8), false);
+        include_mask[[7, 0]] = true;
+        let tiles = IncludedTiles {
+            image: image.view(),
+            unpadded_size: (4, 4),
+            include_mask: Some(include_mask.view()),
+        };
+
+        assert!(tiles.is_needed((3, 0), (3, 3)));
+        assert!(!tiles.is_needed((0, 0), (3, 3)));
+        assert!(!tiles.is_needed((3, 1), (3, 3)));
+        // all padding
+        assert!(!tiles.is_needed((4, 0), (2, 2)));
+    }
+}
@@ -0,0 +1,170 @@
+//! Clean-up of the thresholded mask, before it's dilated
+
+use crate::components::label_components;
+use crate::options::PostProcessingOptions;
+use ndarray::{s, Array2, ArrayView2, Zip};
+use std::ops::Range;
+
+/// Rows (or columns) `y` such that both `y` and `y + offset` are within `0..len`, along with the shifted ones
+fn shifted(len: usize, offset: isize) -> Option<(Range<usize>, Range<usize>)> {
+    let start = (-offset).max(0);
+    let end = len as isize - offset.max(0);
+    (start < end).then(|| {
+        (
+            start as usize..end as usize,
+            (start + offset) as usize..(end + offset) as usize,
+        )
+    })
+}
+
+/// Binary dilation with a symmetric kernel, the pixels outside of the mask don't count
+fn dilate(mask: ArrayView2<bool>, kernel: ArrayView2<bool>) -> Array2<bool> {
+    let (height, width) = mask.dim();
+    let radius = (kernel.nrows() / 2) as isize;
+
+    let mut out = Array2::from_elem((height, width), false);
+    for ((ky, kx), _) in kernel.indexed_iter().filter(|&(_, &on)| on) {
+        let (dy, dx) = (ky as isize - radius, kx as isize - radius);
+        let (Some((rows_out, rows_in)), Some((columns_out, columns_in))) =
+            (shifted(height, dy), shifted(width, dx))
+        else {
+            continue;
+        };
+
+        Zip::from(out.slice_mut(s![rows_out, columns_out]))
+            .and(mask.slice(s![rows_in, columns_in]))
+            .for_each(|out, &value| *out |= value);
+    }
+    out
+}
+
+/// Binary erosion with a symmetric kernel, the pixels outside of the mask don't count
+pub(crate) fn erode(mask: ArrayView2<bool>, kernel: ArrayView2<bool>) -> Array2<bool> {
+    !dilate((!&mask).view(), kernel)
+}
+
+/// Clears the connected regions smaller than `min_area`, except the ones touching the edges of the mask
+fn remove_small_components(mask: &mut Array2<bool>, min_area: usize) {
+    let (height, width) = mask.dim();
+    let labeling = label_components(mask.view());
+
+    let mut keep = vec![false];
+    keep.extend(labeling.components.iter().map(|component| {
+        let touches_edge = component.top == 0
+            || component.left == 0
+            || component.bottom == height
+            || component.right == width;
+        touches_edge || component.area >= min_area
+    }));
+
+    Zip::from(mask)
+        .and(&labeling.labels)
+        .for_each(|value, &label| *value = keep[label as usize]);
+}
+
+/// Sets the holes (regions of unmasked pixels enclosed by the mask) smaller than `max_area`
+fn fill_holes(mask: &mut Array2<bool>, max_area: usize) {
+    let (height, width) = mask.dim();
+    let labeling = label_components((!&*mask).view());
+
+    let mut fill = vec![false];
+    fill.extend(labeling.components.iter().map(|component| {
+        let touches_edge = component.top == 0
+            || component.left == 0
+            || component.bottom == height
+            || component.right == width;
+        !touches_edge && component.area < max_area
+    }));
+
+    Zip::from(mask)
+        .and(&labeling.labels)
+        .for_each(|value, &label| *value |= fill[label as usize]);
+}
+
+/// Applies the enabled steps of the post-processing, in the order of the [`PostProcessingOptions`] fields
+pub fn post_process(mask: &mut Array2<bool>, options: &PostProcessingOptions) {
+    if options.opening_radius > 0 {
+        let kernel = options.kernel_shape.kernel(options.opening_radius);
+        let eroded = erode(mask.view(), kernel.view());
+        *mask = dilate(eroded.view(), kernel.view());
+    }
+    if options.min_area > 0 {
+        remove_small_components(mask, options.min_area);
+    }
+    if options.closing_radius > 0 {
+        let kernel = options.kernel_shape.kernel(options.closing_radius);
+        let dilated = dilate(mask.view(), kernel.view());
+        *mask = erode(dilated.view(), kernel.view());
+    }
+    if options.max_hole_area > 0 {
+        fill_holes(mask, options.max_hole_area);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::options::KernelShape;
+    use ndarray::arr2;
+
+    fn disabled() -> PostProcessingOptions {
+        PostProcessingOptions {
+            opening_radius: 0,
+            min_area: 0,
+            closing_radius: 0,
+            max_hole_area: 0,
+            kernel_shape: KernelShape::Square,
+        }
+    }
+
+    #[test]
+    fn test_small_components_and_holes() {
+        let mut mask = Array2::from_elem((9, 9), false);
+        // a letter with a single pixel hole, a speck, and a speck on the edge
+        mask.slice_mut(s![1..4, 1..4]).fill(true);
+        mask[[2, 2]] = false;
+        mask[[6, 6]] = true;
+        mask[[8, 0]] = true;
+
+        let mut expected = mask.clone();
+        expected[[2, 2]] = true;
+        expected[[6, 6]] = false;
+
+        let options = PostProcessingOptions {
+            min_area: 2,
+            max_hole_area: 2,
+            ..disabled()
+        };
+        post_process(&mut mask, &options);
+        assert_eq!(mask, expected);
+    }
+
+    #[test]
+    fn test_closing_keeps_edges() {
+        // two strokes with a gap between them, right at the mask edge
+        let mut mask = arr2(&[
+            [true, true, false, true, true],
+            [true, true, false, true, true],
+        ]);
+        let options = PostProcessingOptions {
+            closing_radius: 1,
+            ..disabled()
+        };
+        post_process(&mut mask, &options);
+        assert_eq!(mask, Array2::from_elem((2, 5), true));
+
+        // and opening removes the thin line, but not the block
+        let mut mask = Array2::from_elem((6, 8), false);
+        mask.slice_mut(s![0..4, 0..4]).fill(true);
+        mask.slice_mut(s![5, ..]).fill(true);
+        let mut expected = mask.clone();
+        expected.slice_mut(s![5, ..]).fill(false);
+
+        let options = PostProcessingOptions {
+            opening_radius: 1,
+            ..disabled()
+        };
+        post_process(&mut mask, &options);
+        assert_eq!(mask, expected);
+    }
+}
@@ -0,0 +1,127 @@
+use crate::components::label_components;
+use ndarray::{ArrayView2, Zip};
+
+/// A connected region of the text mask
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextRegion {
+    /// Unique within a page, starting from 1, in raster order of the regions' first pixels
+    pub id: usize,
+    pub top: usize,
+    pub left: usize,
+    /// Exclusive
+    pub bottom: usize,
+    /// Exclusive
+    pub right: usize,
+    /// Number of masked pixels in the region
+    pub area: usize,
+    /// Mean model output over the pixels of the region
+    ///
+    /// Dilation adds pixels with low output around the text, so this is lower than the output on the text itself
+    pub mean_confidence: f32,
+    /// Center of mass of the region as (y, x)
+    pub centroid: (f32, f32),
+}
+
+impl TextRegion {
+    pub fn width(&self) -> usize {
+        self.right - self.left
+    }
+
+    pub fn height(&self) -> usize {
+        self.bottom - self.top
+    }
+}
+
+/// Splits the mask into 8-connected regions, gathering their stats from the model output
+pub fn extract_regions(mask: ArrayView2<bool>, probabilities: ArrayView2<f32>) -> Vec<TextRegion> {
+    let labeling = label_components(mask);
+
+    // summed in f64, large regions are well past the point where adding a pixel to a f32 sum is exact
+    let mut sums = vec![(0f64, 0f64, 0f64); labeling.components.len()];
+    Zip::indexed(&labeling.labels)
+        .and(&probabilities)
+        .for_each(|(y, x), &label, &probability| {
+            if label != 0 {
+                let (confidence, sum_y, sum_x) = &mut sums[label as usize - 1];
+                *confidence += probability as f64;
+                *sum_y += y as f64;
+                *sum_x += x as f64;
+            }
+        });
+
+    labeling
+        .components
+        .iter()
+        .zip(sums)
+        .map(|(component, (confidence, sum_y, sum_x))| {
+            let area = component.area as f64;
+            TextRegion {
+                id: component.label as usize,
+                top: component.top,
+                left: component.left,
+                bottom: component.bottom,
+                right: component.right,
+                area: component.area,
+                mean_confidence: (confidence / area) as f32,
+                centroid: ((sum_y / area) as f32, (sum_x / area) as f32),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ndarray::{s, Array2};
+
+    #[test]
+    fn test_extract_regions() {
+        let mut mask = Array2::from_elem((10, 10), false);
+        mask.slice_mut(s![1..3, 1..5]).fill(true);
+        mask[[7, 8]] = true;
+        let mut probabilities = Array2::zeros((10, 10));
+        probabilities.slice_mut(s![1..3, 1..3]).fill(1.0);
+        probabilities[[7, 8]] = 0.5;
+
+        let regions = extract_regions(mask.view(), probabilities.view());
+
+        assert_eq!(
+            regions,
+            vec![
+                TextRegion {
+                    id: 1,
+                    top: 1,
+                    left: 1,
+                    bottom: 3,
+                    right: 5,
+                    area: 8,
+                    mean_confidence: 0.5,
+                    centroid: (1.5, 2.5),
+                },
+                TextRegion {
+                    id: 2,
+                    top: 7,
+                    left: 8,
+                    bottom: 8,
+                    right: 9,
+                    area: 1,
+                    mean_confidence: 0.5,
+                    centroid: (7.0, 8.0),
+                },
+            ]
+        );
+        assert_eq!((regions[0].height(), regions[0].width()), (2, 4));
+    }
+
+    #[test]
+    fn test_large_region() {
+        let mask = Array2::from_elem((2048, 2048), true);
+        let probabilities = Array2::from_elem((2048, 2048), 0.1);
+
+        let regions = extract_regions(mask.view(), probabilities.view());
+
+        assert_eq!(regions.len(), 1);
+        assert!((regions[0].mean_confidence - 0.1).abs() < 1e-6);
+        assert_eq!(regions[0].centroid, (1023.5, 1023.5));
+    }
+}
@@ -0,0 +1,202 @@
+//! Resizing of pages and masks, for running the model at the scale it was trained at
+
+use crate::error::{MangaiError, Result};
+use crate::sample::Sample;
+use ndarray::{Array2, Array3, ArrayView2, ArrayView3, Axis, CowArray, Ix3};
+use tracing::info;
+
+/// Upscaling can't make the page larger than this many pixels (or the original page, if it's larger already)
+pub const MAX_SCALED_PIXELS: usize = 1 << 28;
+
+/// Size of the page after scaling by `scale`, at least a pixel in each dimension
+///
+/// An empty page (with a side of 0 pixels) keeps its size. Fails if `scale` isn't finite and positive, or if the scaled page would be too large, see [`MAX_SCALED_PIXELS`]
+pub fn scaled_size((height, width): (usize, usize), scale: f32) -> Result<(usize, usize)> {
+    if !scale.is_finite() || scale <= 0.0 {
+        return Err(MangaiError::InvalidScale { scale });
+    }
+    if height == 0 || width == 0 {
+        return Ok((height, width));
+    }
+
+    let scaled = |len: usize| (len as f64 * scale as f64).round().max(1.0);
+    let (scaled_height, scaled_width) = (scaled(height), scaled(width));
+    let max_pixels = MAX_SCALED_PIXELS.max(height * width) as f64;
+    if scaled_height * scaled_width > max_pixels {
+        return Err(MangaiError::ScaledPageTooLarge {
+            height: scaled_height as usize,
+            width: scaled_width as usize,
+        });
+    }
+
+    Ok((scaled_height as usize, scaled_width as usize))
+}
+
+/// Contributions of the input pixels to each output pixel, for a triangle filter
+///
+/// When downscaling, the filter is stretched to cover all the input pixels, so that no detail is skipped
+fn weights(from: usize, to: usize) -> Vec<Vec<(usize, f32)>> {
+    let scale = to as f32 / from as f32;
+    let support = if scale < 1.0 { 1.0 / scale } else { 1.0 };
+
+    (0..to)
+        .map(|i| {
+            let center = (i as f32 + 0.5) / scale;
+            let first = (center - support).floor().max(0.0) as usize;
+            let last = ((center + support).ceil() as usize).min(from);
+
+            let mut weights = (first..last)
+                .map(|j| {
+                    let distance = (j as f32 + 0.5 - center).abs() / support;
+                    (j, (1.0 - distance).max(0.0))
+                })
+                .filter(|&(_, weight)| weight > 0.0)
+                .collect::<Vec<_>>();
+            if weights.is_empty() {
+                // can only happen on the very edge, take the closest pixel
+                weights.push(((center as usize).min(from - 1), 1.0));
+            }
+
+            let total = weights.iter().map(|&(_, weight)| weight).sum::<f32>();
+            for (_, weight) in &mut weights {
+                *weight /= total;
+            }
+            weights
+        })
+        .collect()
+}
+
+fn resize_axis(plane: ArrayView2<f32>, axis: Axis, len: usize) -> Array2<f32> {
+    let weights = weights(plane.len_of(axis), len);
+    let mut dim = plane.raw_dim();
+    dim[axis.index()] = len;
+
+    let mut result = Array2::zeros(dim);
+    for (mut out, weights) in result.axis_iter_mut(axis).zip(&weights) {
+        for &(j, weight) in weights {
+            out.scaled_add(weight, &plane.index_axis(axis, j));
+        }
+    }
+    result
+}
+
+/// Resizes a single plane with a (separable) triangle filter
+pub fn resize_plane(plane: ArrayView2<f32>, height: usize, width: usize) -> Array2<f32> {
+    let rows = resize_axis(plane, Axis(1), width);
+    resize_axis(rows.view(), Axis(0), height)
+}
+
+/// Resizes model output back to the page size
+pub fn resize_probabilities(
+    probabilities: Array2<f32>,
+    height: usize,
+    width: usize,
+) -> Array2<f32> {
+    if probabilities.dim() == (height, width) {
+        return probabilities;
+    }
+    resize_plane(probabilities.view(), height, width)
+}
+
+/// Nearest neighbour resize, so that the mask stays crisp
+pub fn resize_mask(mask: Array2<bool>, height: usize, width: usize) -> Array2<bool> {
+    let (from_height, from_width) = mask.dim();
+    if (from_height, from_width) == (height, width) {
+        return mask;
+    }
+    let nearest = |i: usize, from: usize, to: usize| ((i * 2 + 1) * from / (to * 2)).min(from - 1);
+
+    Array2::from_shape_fn((height, width), |(y, x)| {
+        mask[[
+            nearest(y, from_height, height),
+            nearest(x, from_width, width),
+        ]]
+    })
+}
+
+/// Scales the page by `scale`, keeping it as is if the size wouldn't change
+pub fn scale_page<T: Sample>(image: ArrayView3<'_, T>, scale: f32) -> Result<CowArray<'_, T, Ix3>> {
+    let (channels, height, width) = image.dim();
+    let (scaled_height, scaled_width) = scaled_size((height, width), scale)?;
+    if (scaled_height, scaled_width) == (height, width) {
+        return Ok(CowArray::from(image));
+    }
+
+    info!(
+        "Scaling the page from {}x{} to {}x{}",
+        width, height, scaled_width, scaled_height
+    );
+    let mut scaled = Array3::from_elem((channels, scaled_height, scaled_width), T::WHITE);
+    for (plane, mut scaled_plane) in image.outer_iter().zip(scaled.outer_iter_mut()) {
+        let resized = resize_plane(
+            plane.mapv(Sample::to_unit).view(),
+            scaled_height,
+            scaled_width,
+        );
+        scaled_plane.zip_mut_with(&resized, |out, &value| *out = T::from_unit(value));
+    }
+
+    Ok(CowArray::from(scaled))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ndarray::arr2;
+
+    #[test]
+    fn test_downscale_averages() {
+        let plane = arr2(&[[0.0, 1.0, 0.0, 1.0], [0.0, 1.0, 0.0, 1.0]]);
+        let resized = resize_plane(plane.view(), 1, 2);
+
+        assert_eq!(resized.dim(), (1, 2));
+        for &value in &resized {
+            assert!((value - 0.5).abs() < 0.15, "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_resize_mask() {
+        let mask = arr2(&[[true, false], [false, false]]);
+        let resized = resize_mask(mask, 4, 4);
+
+        let mut expected = Array2::from_elem((4, 4), false);
+        expected.slice_mut(ndarray::s![..2, ..2]).fill(true);
+        assert_eq!(resized, expected);
+    }
+
+    #[test]
+    fn test_scale_page_keeps_size() {
+        let image = Array3::from_elem((1, 10, 10), 7u8);
+        let scaled = scale_page(image.view(), 1.01).unwrap();
+        assert!(scaled.is_view());
+
+        let scaled = scale_page(image.view(), 0.5).unwrap();
+        assert_eq!(scaled.dim(), (1, 5, 5));
+        assert!(scaled.iter().all(|&v| v == 7));
+
+        let image = Array3::from_elem((1, 0, 10), 7u8);
+        let scaled = scale_page(image.view(), 0.5).unwrap();
+        assert!(scaled.is_view());
+    }
+
+    #[test]
+    fn test_invalid_scale() {
+        for scale in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+            assert!(matches!(
+                scaled_size((10, 10), scale),
+                Err(MangaiError::InvalidScale { .. })
+            ));
+        }
+        assert!(matches!(
+            scaled_size((10, 10), 1e10),
+            Err(MangaiError::ScaledPageTooLarge { .. })
+        ));
+        assert_eq!(scaled_size((0, 100), 2.0).unwrap(), (0, 100));
+        // downscaling a huge page is fine
+        assert_eq!(
+            scaled_size((1 << 15, 1 << 15), 0.5).unwrap(),
+            (1 << 14, 1 << 14)
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use std::cmp::Ordering;
+use std::fmt::Debug;
+
+/// Type of a single channel value of a page
+///
+/// Integer samples use their full range, with `0` being black and the maximum being white. Floating point samples go
+/// from `0.0` to `1.0`
+pub trait Sample: Copy + PartialOrd + Debug + Send + Sync + 'static {
+    /// White paper
+    const WHITE: Self;
+
+    /// Maps the sample to `0.0..=1.0`
+    fn to_unit(self) -> f32;
+
+    /// Inverse of [`Sample::to_unit`], clamping the values outside of the range
+    fn from_unit(value: f32) -> Self;
+
+    /// Maps the sample to the 8-bit scale, used for the thresholds given in [`crate::CleanOptions`]
+    fn to_u8_scale(self) -> f32 {
+        self.to_unit() * 255.0
+    }
+
+    /// Inverse of [`Sample::to_u8_scale`]
+    fn from_u8_scale(value: f32) -> Self {
+        Self::from_unit(value / 255.0)
+    }
+
+    /// Swaps black and white
+    fn invert(self) -> Self {
+        Self::from_unit(1.0 - self.to_unit())
+    }
+
+    /// Total order of the samples, floating point ones order NaN after everything else like [`f32::total_cmp`]
+    fn total_cmp(&self, other: &Self) -> Ordering;
+}
+
+impl Sample for u8 {
+    const WHITE: Self = u8::MAX;
+
+    fn to_unit(self) -> f32 {
+        self as f32 / u8::MAX as f32
+    }
+
+    fn from_unit(value: f32) -> Self {
+        (value * u8::MAX as f32).round().clamp(0.0, u8::MAX as f32) as u8
+    }
+
+    fn invert(self) -> Self {
+        u8::MAX - self
+    }
+
+    fn total_cmp(&self, other: &Self) -> Ordering {
+        self.cmp(other)
+    }
+}
+
+impl Sample for u16 {
+    const WHITE: Self = u16::MAX;
+
+    fn to_unit(self) -> f32 {
+        self as f32 / u16::MAX as f32
+    }
+
+    fn from_unit(value: f32) -> Self {
+        (value * u16::MAX as f32)
+            .round()
+            .clamp(0.0, u16::MAX as f32) as u16
+    }
+
+    fn invert(self) -> Self {
+        u16::MAX - self
+    }
+
+    fn total_cmp(&self, other: &Self) -> Ordering {
+        self.cmp(other)
+    }
+}
+
+impl Sample for f32 {
+    const WHITE: Self = 1.0;
+
+    fn to_unit(self) -> f32 {
+        self
+    }
+
+    fn from_unit(value: f32) -> Self {
+        value.clamp(0.0, 1.0)
+    }
+
+    fn total_cmp(&self, other: &Self) -> Ordering {
+        f32::total_cmp(self, other)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        for value in 0..=u8::MAX {
+            assert_eq!(u8::from_unit(value.to_unit()), value);
+            assert_eq!(u8::from_u8_scale(value.to_u8_scale()), value);
+        }
+        for value in [0, 1, 257, 32768, u16::MAX] {
+            assert_eq!(u16::from_unit(value.to_unit()), value);
+        }
+        assert_eq!(u16::from_u8_scale(255.0), u16::WHITE);
+        assert_eq!(f32::from_u8_scale(255.0), f32::WHITE);
+        assert_eq!(f32::from_unit(1.5), 1.0);
+    }
+}
@@ -0,0 +1,527 @@
+//! Cleaning a page band by band, without holding the whole page (or its mask) in memory
+//!
+//! The batches come row by row, so once a batch starting at some row is done, no later batch touches the rows above it.
+//! These rows are thresholded, dilated, filled and written out right away, so only a few batch rows of the page are kept
+//! around at any time
+
+use crate::augment;
+use crate::blend::ProbabilityAccumulator;
+use crate::error::{MangaiError, Result};
+use crate::options::{BlendMode, CleanOptions};
+use crate::page::Layout;
+use crate::pipeline::{self, TileSource};
+use crate::sample::Sample;
+use crate::{fill, MangaiClean, ProgressReporter};
+use ndarray::{s, Array2, Array3, ArrayView2, ArrayView3, ArrayViewMut3, Axis, CowArray, Ix3, Zip};
+use std::marker::PhantomData;
+use std::ops::Range;
+
+/// A page which can be read a few rows at a time, in (channels, height, width) layout
+pub trait PageSource<T>: Sync {
+    /// (channels, height, width) of the page
+    fn dim(&self) -> (usize, usize, usize);
+
+    /// Returns the `rows` of the page, with all the channels and the full width
+    fn rows(&self, rows: Range<usize>) -> CowArray<'_, T, Ix3>;
+}
+
+/// Receives the cleaned page, from top to bottom
+pub trait PageSink<T> {
+    /// Writes `rows` (all the channels, full width) starting at the page row `top`
+    fn write_rows(&mut self, top: usize, rows: ArrayView3<T>) -> Result<()>;
+}
+
+impl<'a, T: Sync> PageSource<T> for ArrayView3<'a, T> {
+    fn dim(&self) -> (usize, usize, usize) {
+        ArrayView3::dim(self)
+    }
+
+    fn rows(&self, rows: Range<usize>) -> CowArray<'_, T, Ix3> {
+        CowArray::from(self.slice(s![.., rows, ..]))
+    }
+}
+
+impl<'a, T: Clone> PageSink<T> for ArrayViewMut3<'a, T> {
+    fn write_rows(&mut self, top: usize, rows: ArrayView3<T>) -> Result<()> {
+        let (_, height, _) = rows.dim();
+        let mut out = self.slice_mut(s![.., top..top + height, ..]);
+        MangaiError::check_shape("written rows", out.shape(), rows.shape())?;
+        out.assign(&rows);
+        Ok(())
+    }
+}
+
+/// The model view of a page source: transparent pixels are white, and so is everything past the page edges
+struct ModelTiles<'a, P> {
+    page: &'a P,
+    layout: Layout,
+    height: usize,
+    width: usize,
+    tile_size: (usize, usize),
+}
+
+impl<'a, P> ModelTiles<'a, P> {
+    fn padded_dim(&self) -> (usize, usize) {
+        let (tile_height, tile_width) = self.tile_size;
+        (self.height.max(tile_height), self.width.max(tile_width))
+    }
+}
+
+impl<'a, T: Sample, P: PageSource<T>> TileSource<T> for ModelTiles<'a, P> {
+    fn dim(&self) -> (usize, usize, usize) {
+        let (height, width) = self.padded_dim();
+        (self.layout.color_channels(), height, width)
+    }
+
+    fn tile(&self, (top, left): (usize, usize), size: (usize, usize)) -> CowArray<'_, T, Ix3> {
+        let (height, width) = size;
+        let bottom = (top + height).min(self.height);
+        let right = (left + width).min(self.width);
+
+        let rows = self.page.rows(top..bottom);
+        let colors = self
+            .layout
+            .visible_colors(rows.slice(s![.., .., left..right]));
+        if colors.dim() == (self.layout.color_channels(), height, width) {
+            return CowArray::from(colors.into_owned());
+        }
+
+        let mut tile = Array3::from_elem((self.layout.color_channels(), height, width), T::WHITE);
+        tile.slice_mut(s![.., ..bottom - top, ..right - left])
+            .assign(&colors);
+        CowArray::from(tile)
+    }
+}
+
+/// Consecutive full-width rows of a page-sized 2D array
+struct Rows<A> {
+    /// Page row of the first row of `data`
+    top: usize,
+    data: Array2<A>,
+}
+
+impl<A: Clone> Rows<A> {
+    fn new(width: usize) -> Self {
+        Self {
+            top: 0,
+            data: Array2::from_shape_vec((0, width), Vec::new()).unwrap(),
+        }
+    }
+
+    /// Page row right below the last kept one
+    fn bottom(&self) -> usize {
+        self.top + self.data.nrows()
+    }
+
+    fn push(&mut self, rows: ArrayView2<A>) {
+        self.data.append(Axis(0), rows).unwrap();
+    }
+
+    /// Pushes rows of `value` until the page row `bottom`
+    fn grow(&mut self, bottom: usize, value: A) {
+        if bottom > self.bottom() {
+            let rows = Array2::from_elem((bottom - self.bottom(), self.data.ncols()), value);
+            self.push(rows.view());
+        }
+    }
+
+    fn view(&self, rows: Range<usize>) -> ArrayView2<'_, A> {
+        self.data
+            .slice(s![rows.start - self.top..rows.end - self.top, ..])
+    }
+
+    /// Forgets the rows above the page row `row`
+    fn drop_above(&mut self, row: usize) {
+        if row > self.top {
+            let count = (row - self.top).min(self.data.nrows());
+            self.data = self.data.slice(s![count.., ..]).to_owned();
+            self.top += count;
+        }
+    }
+}
+
+/// Turns the final rows of the model output into final rows of the cleaned page
+struct Bands<'a, T, P, K> {
+    clean: &'a MangaiClean,
+    page_in: &'a P,
+    page_out: &'a mut K,
+    layout: Layout,
+    options: &'a CleanOptions,
+    /// Size of the page
+    height: usize,
+    width: usize,
+    /// Height of the page padded to the batch size, the mask is computed for the padding too
+    padded_height: usize,
+    /// Final rows of the model output, kept as long as their dilation can reach the rows not thresholded yet
+    probabilities: Rows<f32>,
+    /// Rows of the (padded) mask, the ones above `mask_done` are final
+    mask: Rows<bool>,
+    mask_done: usize,
+    /// Rows above this are written to `page_out`
+    written: usize,
+    _sample: PhantomData<T>,
+}
+
+impl<'a, T: Sample, P: PageSource<T>, K: PageSink<T>> Bands<'a, T, P, K> {
+    /// ORs the mask of a batch at `origin` (top, left), with [`BlendMode::Or`]
+    fn add_mask(&mut self, (top, left): (usize, usize), batch_mask: ArrayView2<bool>) {
+        let (height, width) = batch_mask.dim();
+        self.mask.grow(top + height, false);
+
+        let top = top - self.mask.top;
+        let mut mask_out = self
+            .mask
+            .data
+            .slice_mut(s![top..top + height, left..left + width]);
+        Zip::from(&mut mask_out).and(&batch_mask).for_each(|a, &b| {
+            if b {
+                *a = true;
+            }
+        });
+    }
+
+    /// Marks the mask rows above `bottom` as final, with [`BlendMode::Or`]
+    fn finish_mask(&mut self, bottom: usize) -> Result<()> {
+        self.mask.grow(bottom, false);
+        self.mask_done = self.mask_done.max(bottom);
+        self.write_bands(false)
+    }
+
+    /// Adds the next final rows of the model output, with the other blend modes
+    fn add_probabilities(&mut self, rows: ArrayView2<f32>, is_last: bool) -> Result<()> {
+        self.probabilities.push(rows);
+
+        // rows closer than this to the edge of the thresholded window might be missing some dilation
+        let margin = self.options.dilation_reach() + self.options.dilation_radius;
+        let available = self.probabilities.bottom();
+        let done = if is_last {
+            self.padded_height
+        } else {
+            available.saturating_sub(margin)
+        };
+
+        if done > self.mask_done {
+            let window_top = self.mask_done.saturating_sub(margin);
+            let window_mask = self.clean.threshold_probabilities(
+                self.probabilities.view(window_top..available),
+                self.options,
+            );
+            self.mask
+                .push(window_mask.slice(s![self.mask_done - window_top..done - window_top, ..]));
+            self.mask_done = done;
+            self.probabilities.drop_above(done.saturating_sub(margin));
+        }
+
+        self.write_bands(is_last)
+    }
+
+    /// Fills and writes out the rows whose fill can't be affected by the mask rows which are not final yet
+    fn write_bands(&mut self, is_last: bool) -> Result<()> {
+        let reach = self.options.fill_mode.reach();
+        let done = if is_last {
+            self.height
+        } else {
+            self.mask_done.saturating_sub(reach).min(self.height)
+        };
+        if done <= self.written {
+            return Ok(());
+        }
+
+        // the fill looks around the band, so give it the context it would see on the whole page
+        let window = self.written.saturating_sub(reach)..(done + reach).min(self.height);
+        let image_in = self.page_in.rows(window.clone());
+        let mut mask = self
+            .mask
+            .view(window.clone())
+            .slice(s![.., ..self.width])
+            .to_owned();
+        if let Some(opaque) = self.layout.opaque(image_in.view()) {
+            mask &= &opaque;
+        }
+
+        // the alpha channel goes to the output as is
+        let mut image_out = image_in.to_owned();
+        fill::fill_page(
+            self.layout.colors(image_in.view()),
+            mask.view(),
+            self.layout.colors_mut(image_out.view_mut()),
+            self.options,
+        );
+
+        let band = self.written - window.start..done - window.start;
+        self.page_out
+            .write_rows(self.written, image_out.slice(s![.., band, ..]))?;
+        self.written = done;
+        self.mask.drop_above(done.saturating_sub(reach));
+
+        Ok(())
+    }
+}
+
+/// Checks that `options` don't need the whole page at once
+fn check_options(options: &CleanOptions) -> Result<()> {
+    let unsupported = if options.bubbles.is_some() {
+        Some("bubble detection")
+    } else if options.detect_inverted {
+        Some("inverted text detection")
+    } else if options.resolution.is_some() {
+        Some("resampling to the model resolution")
+    } else if options.post_processing.is_some() && options.blend_mode != BlendMode::Or {
+        // the connected components can span any number of bands
+        Some("mask post-processing of the whole page")
+    } else {
+        None
+    };
+
+    match unsupported {
+        Some(option) => Err(MangaiError::UnsupportedOption {
+            option,
+            operation: "streaming cleaning",
+        }),
+        None => Ok(()),
+    }
+}
+
+/// See [`MangaiClean::clean_page_streaming`]
+pub(crate) fn clean_page<T: Sample>(
+    clean: &MangaiClean,
+    page_in: &impl PageSource<T>,
+    page_out: &mut impl PageSink<T>,
+    options: &CleanOptions,
+    progress_reporter: &mut dyn ProgressReporter,
+) -> Result<()> {
+    check_options(options)?;
+    options.fill_mode.check()?;
+
+    let (channels, height, width) = page_in.dim();
+    let layout = Layout::from_channels(channels)?;
+    let tiles = ModelTiles {
+        page: page_in,
+        layout,
+        height,
+        width,
+        tile_size: clean.tile_size(),
+    };
+    let (padded_height, padded_width) = tiles.padded_dim();
+
+    let mut bands = Bands {
+        clean,
+        page_in,
+        page_out,
+        layout,
+        options,
+        height,
+        width,
+        padded_height,
+        probabilities: Rows::new(padded_width),
+        mask: Rows::new(padded_width),
+        mask_done: 0,
+        written: 0,
+        _sample: PhantomData,
+    };
+
+    if options.blend_mode == BlendMode::Or {
+        pipeline::run_batches(
+            &tiles,
+            options.num_workers,
+            clean.group_size(options),
+            clean.tile_size(),
+            options.tile_margin,
+            progress_reporter,
+            |images_in| clean.detect_batch_masks(images_in, options),
+            |batch_mask, origin| {
+                bands.finish_mask(origin.0)?;
+                bands.add_mask(origin, batch_mask.view());
+                Ok(())
+            },
+        )?;
+
+        bands.finish_mask(padded_height)?;
+        bands.write_bands(true)
+    } else {
+        let mut accumulator =
+            ProbabilityAccumulator::new_streaming(padded_height, padded_width, options.blend_mode);
+        let mut taken = 0;
+
+        pipeline::run_batches(
+            &tiles,
+            options.num_workers,
+            clean.group_size(options),
+            clean.tile_size(),
+            options.tile_margin,
+            progress_reporter,
+            |images_in| augment::run_augmented(clean, images_in, options),
+            |batch_probabilities, origin| {
+                let (top, _) = origin;
+                if top > taken {
+                    bands.add_probabilities(accumulator.take_rows(top).view(), false)?;
+                    taken = top;
+                }
+                accumulator.add(origin, batch_probabilities.view());
+                Ok(())
+            },
+        )?;
+
+        bands.add_probabilities(accumulator.finish().view(), true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::options::{BubbleOptions, FillMode, PostProcessingOptions, Resolution};
+    use crate::NoProgress;
+
+    /// Checks that the rows come top to bottom, without gaps or overlaps
+    struct OrderedSink {
+        page: Array3<u8>,
+        written: usize,
+    }
+
+    impl PageSink<u8> for OrderedSink {
+        fn write_rows(&mut self, top: usize, rows: ArrayView3<u8>) -> Result<()> {
+            assert_eq!(top, self.written);
+            self.written += rows.len_of(Axis(1));
+            self.page.view_mut().write_rows(top, rows)
+        }
+    }
+
+    /// Several rows and columns of tiles, with text crossing their edges
+    fn page() -> Array3<u8> {
+        let mut page = Array3::from_elem((1, 230, 130), 230u8);
+        for (i, y) in (3..220).step_by(17).enumerate() {
+            let x = (i * 23) % 110;
+            page.slice_mut(s![.., y..y + 8, x..x + 20]).fill(20);
+        }
+        page
+    }
+
+    #[test]
+    fn test_same_as_clean_page() {
+        let clean = MangaiClean::stub((64, 48));
+        let page = page();
+
+        let cases = [
+            CleanOptions::default(),
+            CleanOptions {
+                blend_mode: BlendMode::EdgeWeighted,
+                num_workers: 3,
+                tile_margin: 8,
+                ..Default::default()
+            },
+            CleanOptions {
+                fill_mode: FillMode::Background { ring_width: 4 },
+                num_workers: 2,
+                tile_margin: 5,
+                ..Default::default()
+            },
+            CleanOptions {
+                blend_mode: BlendMode::Average,
+                fill_mode: FillMode::Background { ring_width: 4 },
+                tile_margin: 3,
+                max_batch_size: 3,
+                ..Default::default()
+            },
+        ];
+        for options in cases {
+            let mut expected = Array3::zeros(page.dim());
+            clean
+                .clean_page(
+                    page.view(),
+                    expected.view_mut(),
+                    None,
+                    None,
+                    &options,
+                    &mut NoProgress,
+                )
+                .unwrap();
+            // the text got cleaned
+            assert_ne!(expected, page);
+
+            let mut sink = OrderedSink {
+                page: Array3::zeros(page.dim()),
+                written: 0,
+            };
+            clean
+                .clean_page_streaming(&page.view(), &mut sink, &options, &mut NoProgress)
+                .unwrap();
+            assert_eq!(sink.written, page.dim().1, "{:?}", options);
+            assert_eq!(sink.page, expected, "{:?}", options);
+        }
+    }
+
+    #[test]
+    fn test_check_options() {
+        let unsupported = [
+            CleanOptions {
+                bubbles: Some(BubbleOptions::default()),
+                ..Default::default()
+            },
+            CleanOptions {
+                detect_inverted: true,
+                ..Default::default()
+            },
+            CleanOptions {
+                resolution: Some(Resolution::Dpi {
+                    page: 300.0,
+                    target: 600.0,
+                }),
+                ..Default::default()
+            },
+            CleanOptions {
+                post_processing: Some(PostProcessingOptions::default()),
+                blend_mode: BlendMode::Max,
+                ..Default::default()
+            },
+        ];
+        for options in unsupported {
+            assert!(
+                matches!(
+                    check_options(&options),
+                    Err(MangaiError::UnsupportedOption { .. })
+                ),
+                "{:?}",
+                options
+            );
+        }
+
+        let supported = CleanOptions {
+            post_processing: Some(PostProcessingOptions::default()),
+            ..Default::default()
+        };
+        assert!(check_options(&supported).is_ok());
+    }
+
+    #[test]
+    fn test_rows() {
+        let mut rows = Rows::new(2);
+        rows.grow(3, false);
+        rows.drop_above(2);
+        rows.push(ndarray::arr2(&[[true, false]]).view());
+
+        assert_eq!((rows.top, rows.bottom()), (2, 4));
+        assert_eq!(rows.view(3..4), ndarray::arr2(&[[true, false]]));
+    }
+
+    #[test]
+    fn test_model_tiles() {
+        let mut page = Array3::from_elem((2, 12, 5), 7u8);
+        page[[1, 0, 0]] = 0;
+        let page = page.view();
+        let tiles = ModelTiles {
+            page: &page,
+            layout: Layout::GrayAlpha,
+            height: 12,
+            width: 5,
+            tile_size: (8, 8),
+        };
+
+        assert_eq!(tiles.dim(), (1, 12, 8));
+        let tile = tiles.tile((0, 0), (8, 8));
+        assert_eq!(tile.dim(), (1, 8, 8));
+        // transparent and padded pixels are white
+        assert_eq!(tile[[0, 0, 0]], 255);
+        assert_eq!(tile[[0, 0, 1]], 7);
+        assert_eq!(tile[[0, 0, 5]], 255);
+    }
+}
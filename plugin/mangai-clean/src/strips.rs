@@ -0,0 +1,149 @@
+//! Vertical strips (like the slices of a webtoon chapter) presented as a single continuous page
+
+use crate::error::{MangaiError, Result};
+use crate::streaming::{PageSink, PageSource};
+use ndarray::{s, Array3, ArrayView3, ArrayViewMut3, Axis, CowArray, Ix3};
+use std::ops::Range;
+
+/// Page rows where each of the strips starts, followed by the height of the whole page
+///
+/// All the strips must have the same number of channels and width
+fn strip_tops(dims: impl Iterator<Item = (usize, usize, usize)>) -> Result<Vec<usize>> {
+    let mut tops = vec![0];
+    let mut first = None;
+    for (channels, height, width) in dims {
+        let first = *first.get_or_insert((channels, width));
+        MangaiError::check_shape("strip", &[first.0, first.1], &[channels, width])?;
+        tops.push(tops.last().unwrap() + height);
+    }
+    Ok(tops)
+}
+
+/// Strips of the page overlapping with `rows`, with the overlapping part in the strip coordinates
+fn overlapping(
+    tops: &[usize],
+    rows: Range<usize>,
+) -> impl Iterator<Item = (usize, Range<usize>)> + '_ {
+    tops.windows(2).enumerate().filter_map(move |(i, bounds)| {
+        let start = rows.start.max(bounds[0]);
+        let end = rows.end.min(bounds[1]);
+        (start < end).then(|| (i, start - bounds[0]..end - bounds[0]))
+    })
+}
+
+pub struct Strips<'a, 'b, T> {
+    strips: &'b [ArrayView3<'a, T>],
+    tops: Vec<usize>,
+}
+
+impl<'a, 'b, T> Strips<'a, 'b, T> {
+    pub fn new(strips: &'b [ArrayView3<'a, T>]) -> Result<Self> {
+        let tops = strip_tops(strips.iter().map(|strip| strip.dim()))?;
+        Ok(Self { strips, tops })
+    }
+}
+
+impl<'a, 'b, T: Clone + Sync> PageSource<T> for Strips<'a, 'b, T> {
+    fn dim(&self) -> (usize, usize, usize) {
+        let (channels, _, width) = self.strips.first().map_or((0, 0, 0), |strip| strip.dim());
+        (channels, *self.tops.last().unwrap(), width)
+    }
+
+    fn rows(&self, rows: Range<usize>) -> CowArray<'_, T, Ix3> {
+        let mut parts = overlapping(&self.tops, rows)
+            .map(|(i, rows)| self.strips[i].slice(s![.., rows, ..]))
+            .collect::<Vec<_>>();
+
+        if parts.len() == 1 {
+            // no need to copy the rows within a single strip
+            return CowArray::from(parts.remove(0));
+        }
+
+        // (part, row in the part) of each of the rows, there are none for an empty range
+        let part_rows = parts
+            .iter()
+            .enumerate()
+            .flat_map(|(i, part)| (0..part.len_of(Axis(1))).map(move |y| (i, y)))
+            .collect::<Vec<_>>();
+        let (channels, _, width) = self.dim();
+        CowArray::from(Array3::from_shape_fn(
+            (channels, part_rows.len(), width),
+            |(c, y, x)| {
+                let (i, y) = part_rows[y];
+                parts[i][[c, y, x]].clone()
+            },
+        ))
+    }
+}
+
+pub struct StripsMut<'a, 'b, T> {
+    strips: &'b mut [ArrayViewMut3<'a, T>],
+    tops: Vec<usize>,
+}
+
+impl<'a, 'b, T> StripsMut<'a, 'b, T> {
+    pub fn new(strips: &'b mut [ArrayViewMut3<'a, T>]) -> Result<Self> {
+        let tops = strip_tops(strips.iter().map(|strip| strip.dim()))?;
+        Ok(Self { strips, tops })
+    }
+}
+
+impl<'a, 'b, T: Clone> PageSink<T> for StripsMut<'a, 'b, T> {
+    fn write_rows(&mut self, top: usize, rows: ArrayView3<T>) -> Result<()> {
+        let (_, height, _) = rows.dim();
+        for (i, strip_rows) in overlapping(&self.tops, top..top + height) {
+            let start = self.tops[i] + strip_rows.start - top;
+            let part = rows.slice(s![.., start..start + strip_rows.len(), ..]);
+            self.strips[i].write_rows(strip_rows.start, part)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rows_across_strips() {
+        let first = Array3::from_elem((1, 2, 3), 1u8);
+        let second = Array3::from_elem((1, 3, 3), 2u8);
+        let strips = [first.view(), second.view()];
+        let page = Strips::new(&strips).unwrap();
+
+        assert_eq!(page.dim(), (1, 5, 3));
+        let rows = page.rows(1..4);
+        assert_eq!(rows.index_axis(Axis(2), 0), ndarray::arr2(&[[1, 2, 2]]));
+
+        let mut first_out = Array3::zeros((1, 2, 3));
+        let mut second_out = Array3::zeros((1, 3, 3));
+        let mut strips_out = [first_out.view_mut(), second_out.view_mut()];
+        let mut page_out = StripsMut::new(&mut strips_out).unwrap();
+        page_out.write_rows(0, page.rows(0..3).view()).unwrap();
+        page_out.write_rows(3, page.rows(3..5).view()).unwrap();
+
+        assert_eq!(first_out, first);
+        assert_eq!(second_out, second);
+    }
+
+    #[test]
+    fn test_empty_rows() {
+        let empty = Array3::<u8>::zeros((1, 0, 3));
+        let strips = [empty.view()];
+        let page = Strips::new(&strips).unwrap();
+        assert_eq!(page.rows(0..0).dim(), (1, 0, 3));
+
+        let first = Array3::<u8>::zeros((1, 2, 3));
+        let strips = [first.view(), empty.view(), first.view()];
+        let page = Strips::new(&strips).unwrap();
+        assert_eq!(page.rows(2..2).dim(), (1, 0, 3));
+        assert_eq!(page.rows(1..3).dim(), (1, 2, 3));
+    }
+
+    #[test]
+    fn test_mismatched_strips() {
+        let first = Array3::<u8>::zeros((1, 2, 3));
+        let second = Array3::<u8>::zeros((1, 2, 4));
+        assert!(Strips::new(&[first.view(), second.view()]).is_err());
+    }
+}